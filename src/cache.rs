@@ -0,0 +1,174 @@
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+use tokio::sync::OnceCell;
+
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the cache in `dir` instead of `~/.wasp`; only the first call counts
+pub fn set_dir(dir: PathBuf) {
+    let _ = CACHE_DIR.set(dir);
+}
+
+/// Remembers which module id the API returned for a given module's contents
+///
+/// Entries are keyed by API and the SHA-256 of the uploaded bytes so identical
+/// modules are only uploaded once per API.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct UploadCache {
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CacheEntry {
+    pub module_id: String,
+    pub uploaded_at: SystemTime,
+}
+
+impl UploadCache {
+    pub fn path() -> Result<PathBuf, Error> {
+        let dir = match CACHE_DIR.get() {
+            Some(dir) => dir.clone(),
+            None => crate::wasp_dir()?,
+        };
+        Ok(dir.join("upload-cache.json"))
+    }
+
+    pub fn load() -> Result<Self, Error> {
+        match std::fs::read(Self::path()?) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, api: &str, hash: &str) -> Option<&str> {
+        self.entries
+            .get(&key(api, hash))
+            .map(|entry| entry.module_id.as_str())
+    }
+
+    /// Every entry as `(api, hash, entry)`
+    pub fn entries(&self) -> Vec<(String, String, CacheEntry)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| {
+                let (api, hash) = key.rsplit_once(' ')?;
+                Some((api.to_owned(), hash.to_owned(), entry.clone()))
+            })
+            .collect()
+    }
+
+    pub fn remove(&mut self, api: &str, hash: &str) -> bool {
+        self.entries.remove(&key(api, hash)).is_some()
+    }
+
+    pub fn insert(&mut self, api: &str, hash: &str, module_id: String) {
+        self.entries.insert(
+            key(api, hash),
+            CacheEntry {
+                module_id,
+                uploaded_at: SystemTime::now(),
+            },
+        );
+    }
+}
+
+/// An `UploadCache` shared by concurrent tasks
+///
+/// Uploads of the same contents are coalesced: the first task to ask for a
+/// new hash uploads it and the others wait for its module id.
+pub struct SharedUploadCache {
+    cache: Mutex<UploadCache>,
+    pending: Mutex<HashMap<String, Arc<OnceCell<String>>>>,
+}
+
+impl SharedUploadCache {
+    pub fn new(cache: UploadCache) -> Self {
+        Self {
+            cache: Mutex::new(cache),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn into_inner(self) -> UploadCache {
+        self.cache
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Saves the entries so far, even while uploads are still sharing the cache
+    pub fn save(&self) -> Result<(), Error> {
+        self.lock_cache().save()
+    }
+
+    pub fn get(&self, api: &str, hash: &str) -> Option<String> {
+        self.lock_cache().get(api, hash).map(str::to_owned)
+    }
+
+    /// Returns the cached module id for `hash`, running `upload` at most once
+    /// per hash when there is none
+    pub async fn get_or_upload<F, Fut>(
+        &self,
+        api: &str,
+        hash: &str,
+        upload: F,
+    ) -> Result<String, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, Error>>,
+    {
+        let cell = self
+            .pending
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(key(api, hash))
+            .or_default()
+            .clone();
+
+        let module_id = cell
+            .get_or_try_init(|| async {
+                match self.get(api, hash) {
+                    Some(module_id) => Ok(module_id),
+                    None => upload().await,
+                }
+            })
+            .await?
+            .clone();
+
+        self.lock_cache().insert(api, hash, module_id.clone());
+
+        Ok(module_id)
+    }
+
+    fn lock_cache(&self) -> std::sync::MutexGuard<UploadCache> {
+        self.cache.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Hex encoded SHA-256 of a module's contents
+pub fn hash(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+fn key(api: &str, hash: &str) -> String {
+    format!("{} {}", api, hash)
+}
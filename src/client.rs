@@ -0,0 +1,650 @@
+use crate::{cache, logger};
+use failure::{format_err, Error, Fail};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Credentials and connection settings for one account on a wasp API
+#[derive(Clone)]
+pub struct Client {
+    service: String,
+    account: String,
+    retries: u32,
+    retry_delay: Duration,
+    http2: bool,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    proxy_auth: Option<String>,
+    max_redirects: Option<usize>,
+    dump_request: Option<PathBuf>,
+    signing_key: Option<String>,
+}
+
+impl Client {
+    pub fn new(
+        service: String,
+        account: String,
+        retries: u32,
+        retry_delay: Duration,
+        http2: bool,
+        connect_timeout: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            service,
+            account,
+            retries,
+            retry_delay,
+            http2,
+            connect_timeout,
+            timeout,
+            proxy_auth: None,
+            max_redirects: None,
+            dump_request: None,
+            signing_key: None,
+        }
+    }
+
+    /// Adds `X-Wasp-Signature` and `X-Wasp-Timestamp` to every API request,
+    /// all of which go through `send_with_retry`
+    pub fn with_signing_key(mut self, key: Option<String>) -> Self {
+        self.signing_key = key;
+        self
+    }
+
+    /// Records every request sent through `send_with_retry` in `file`
+    pub fn with_dump_request(mut self, file: Option<PathBuf>) -> Self {
+        self.dump_request = file;
+        self
+    }
+
+    /// Limits the redirects followed per request, with `None` keeping
+    /// reqwest's default of 10
+    pub fn with_max_redirects(mut self, max_redirects: Option<usize>) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Sends `credential` as Proxy-Authorization with every request to the API
+    pub fn with_proxy_auth(mut self, credential: Option<String>) -> Self {
+        self.proxy_auth = credential;
+        self
+    }
+
+    /// Starts a reqwest client with the protocol settings shared by every request
+    ///
+    /// HTTP/2 is negotiated over ALPN by default; `--http2` skips the
+    /// negotiation and assumes the server speaks it. The connect timeout only
+    /// covers reaching the server, so slow responses are bounded by `timeout`
+    /// alone.
+    pub(crate) fn builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder();
+        if self.http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        match self.max_redirects {
+            Some(0) => builder = builder.redirect(reqwest::redirect::Policy::none()),
+            Some(max) => builder = builder.redirect(reqwest::redirect::Policy::limited(max)),
+            None => {}
+        }
+        builder
+    }
+
+    /// Like `builder`, plus the headers every request to the API needs
+    ///
+    /// Module downloads go through `builder` instead so the gateway
+    /// credential isn't sent to other servers.
+    pub fn api_builder(&self) -> Result<reqwest::ClientBuilder, Error> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(credential) = &self.proxy_auth {
+            let mut value = reqwest::header::HeaderValue::from_str(credential)?;
+            // keeps it out of Debug output
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::PROXY_AUTHORIZATION, value);
+        }
+        Ok(self.builder().default_headers(headers))
+    }
+
+    /// The API base URL, e.g. `https://api.wasp.ws`
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// The keyring account holding the access token
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn keyring(&self) -> keyring::Keyring {
+        keyring::Keyring::new(&self.service, &self.account)
+    }
+
+    /// Stores a token along with how far the local clock was off at login
+    pub fn set(
+        &self,
+        access_token: String,
+        expires_in: u64,
+        clock_skew: Option<i64>,
+    ) -> Result<(), Error> {
+        self.keyring()
+            .set_password(&serde_json::to_string(&KeyringEntry {
+                access_token,
+                expires_at: SystemTime::now() + Duration::from_secs(expires_in),
+                clock_skew,
+            })?)
+            .map_err(|err| format_err!("{}", err))?;
+        Ok(())
+    }
+
+    /// The access token from `WASP_TOKEN` or, without it, the keyring
+    pub fn get_password(&self) -> Result<String, Error> {
+        if let Ok(token) = std::env::var("WASP_TOKEN") {
+            return Ok(token);
+        }
+
+        let entry = self.keyring().get_password().map_err(|err| match err {
+            keyring::KeyringError::NoPasswordFound if self.account == "default" => {
+                AuthError("No account found. Log in with `wasp login USERNAME`.".to_owned())
+            }
+            keyring::KeyringError::NoPasswordFound => AuthError(format!(
+                "No account found. Log in with `wasp login USERNAME --account {}`.",
+                self.account
+            )),
+            keyring::KeyringError::NoBackendFound => {
+                AuthError("No system keyring found; install one or set WASP_TOKEN.".to_owned())
+            }
+            // backends report these as platform specific errors, so go by
+            // their messages
+            err if is_keyring_locked(&err) => {
+                AuthError("Your system keyring is locked; unlock it or set WASP_TOKEN.".to_owned())
+            }
+            _ => AuthError(err.to_string()),
+        })?;
+        let entry: KeyringEntry = serde_json::from_str(&entry)?;
+
+        if entry.expires_at < SystemTime::now() {
+            let mut message = "Login token is expired. Log in again with `wasp login`.".to_owned();
+            // a wrong clock makes fresh tokens look expired, which logging in
+            // again doesn't fix
+            if let Some(skew) = entry.clock_skew.and_then(describe_clock_skew) {
+                message = format!(
+                    "{} At login {}; if the token keeps expiring, check that NTP \
+                     is keeping the system clock in sync.",
+                    message, skew
+                );
+            }
+            return Err(AuthError(message).into());
+        }
+
+        Ok(entry.access_token)
+    }
+
+    pub fn delete(&self) -> Result<(), Error> {
+        self.keyring()
+            .delete_password()
+            .map_err(|err| format_err!("{}", err))?;
+        Ok(())
+    }
+
+    pub async fn client(&self) -> Result<reqwest::Client, Error> {
+        let access_token = self.get_password()?;
+
+        let client = self
+            .api_builder()?
+            .gzip(true)
+            .default_headers({
+                use reqwest::header::HeaderMap;
+                let mut headers = HeaderMap::new();
+                headers.insert("Authorization", format!("Bearer {}", access_token).parse()?);
+                headers
+            })
+            .build()?;
+
+        Ok(client)
+    }
+
+    pub fn url<T: std::fmt::Display>(&self, path: T) -> String {
+        format!("{}{}", self.service, path)
+    }
+
+    pub async fn get<T: std::fmt::Display>(
+        &self,
+        path: T,
+    ) -> Result<reqwest::RequestBuilder, Error> {
+        let url = self.url(path);
+        debug!("GET {}", url);
+        Ok(self
+            .client()
+            .await?
+            .get(&url)
+            .header(REQUEST_ID, new_request_id()))
+    }
+
+    pub async fn post<T: std::fmt::Display>(
+        &self,
+        path: T,
+    ) -> Result<reqwest::RequestBuilder, Error> {
+        let url = self.url(path);
+        debug!("POST {}", url);
+        Ok(self
+            .client()
+            .await?
+            .post(&url)
+            .header(REQUEST_ID, new_request_id()))
+    }
+
+    pub async fn put<T: std::fmt::Display>(
+        &self,
+        path: T,
+    ) -> Result<reqwest::RequestBuilder, Error> {
+        let url = self.url(path);
+        debug!("PUT {}", url);
+        Ok(self
+            .client()
+            .await?
+            .put(&url)
+            .header(REQUEST_ID, new_request_id()))
+    }
+
+    /// Sends the request built by `request`, retrying transient failures.
+    ///
+    /// Connection errors and 429/503 responses mean the server didn't act on
+    /// the request, so they're always retried. Timeouts and 502/504 responses
+    /// may come after it did, so they're only retried for GET, HEAD and
+    /// OPTIONS or requests carrying an Idempotency-Key. Any other response is
+    /// returned as is. The request is rebuilt for each attempt so streaming
+    /// bodies can be reopened.
+    pub async fn send_with_retry<F, Fut>(&self, request: F) -> Result<reqwest::Response, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<reqwest::RequestBuilder, Error>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let (client, request) = request().await?.build_split();
+            let mut request = request?;
+            if let Some(key) = &self.signing_key {
+                sign_request(key, &mut request)?;
+            }
+            let method = request.method().to_string();
+            let url = request.url().to_string();
+            let request_id = request.headers().get(REQUEST_ID).cloned();
+            let replayable = matches!(
+                *request.method(),
+                reqwest::Method::GET | reqwest::Method::HEAD | reqwest::Method::OPTIONS
+            ) || request.headers().contains_key("Idempotency-Key");
+
+            if let Some(file) = &self.dump_request {
+                // a debugging aid shouldn't fail the command
+                if let Err(err) = dump_request(file, &request) {
+                    warn!("Could not write {}: {}", file.display(), err);
+                }
+            }
+
+            let result = client
+                .execute(request)
+                .await
+                .map(|response| keep_request_id(response, request_id));
+            logger::request(
+                &method,
+                &url,
+                result
+                    .as_ref()
+                    .ok()
+                    .map(|response| response.status().as_u16()),
+            );
+
+            let is_transient = match &result {
+                Ok(response) => is_transient_status(response.status(), replayable),
+                Err(err) => err.is_connect() || (replayable && err.is_timeout()),
+            };
+
+            if !is_transient || attempt >= self.retries {
+                return Ok(result?);
+            }
+
+            let delay = self
+                .retry_delay
+                .checked_mul(2u32.pow(attempt.min(16)))
+                .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+            attempt += 1;
+
+            info!(
+                "Request failed; retrying in {}ms ({}/{})",
+                delay.as_millis(),
+                attempt,
+                self.retries
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Longest wait between two attempts, however large `--retry-delay` is
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Whether a response is worth retrying; gateway errors only when
+/// `replayable`, as the API may have handled the request behind them
+fn is_transient_status(status: reqwest::StatusCode, replayable: bool) -> bool {
+    use reqwest::StatusCode;
+
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+        StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => replayable,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct KeyringEntry {
+    access_token: String,
+    expires_at: SystemTime,
+
+    /// Seconds the local clock was ahead of the API's at login, if known
+    #[serde(default)]
+    clock_skew: Option<i64>,
+}
+
+/// Clock differences up to this are normal network and rounding noise
+const MAX_CLOCK_SKEW: u64 = 60;
+
+/// Seconds the local clock is ahead of `server_time`, negative when behind
+pub(crate) fn clock_skew(server_time: SystemTime) -> i64 {
+    match SystemTime::now().duration_since(server_time) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    }
+}
+
+/// Describes a skew worth mentioning, e.g. "the system clock was 3600s
+/// ahead of the API's"
+pub(crate) fn describe_clock_skew(skew: i64) -> Option<String> {
+    if skew.unsigned_abs() <= MAX_CLOCK_SKEW {
+        return None;
+    }
+    let direction = if skew > 0 { "ahead of" } else { "behind" };
+    Some(format!(
+        "the system clock was {}s {} the API's",
+        skew.unsigned_abs(),
+        direction
+    ))
+}
+
+/// Local credentials are missing or expired
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct AuthError(pub String);
+
+/// Error response returned by the wasp API
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct ApiError {
+    pub status: reqwest::StatusCode,
+    pub message: String,
+}
+
+pub fn is_not_found(err: &Error) -> bool {
+    err.downcast_ref::<ApiError>()
+        .map_or(false, |err| err.status == reqwest::StatusCode::NOT_FOUND)
+}
+
+pub fn is_conflict(err: &Error) -> bool {
+    err.downcast_ref::<ApiError>()
+        .map_or(false, |err| err.status == reqwest::StatusCode::CONFLICT)
+}
+
+/// Signs the method, URL and current time so the API can reject tampered or
+/// replayed requests
+///
+/// `X-Wasp-Signature` is the hex HMAC-SHA256 of `METHOD + URL + TIMESTAMP`,
+/// with the Unix timestamp in seconds sent as `X-Wasp-Timestamp`. Each retry
+/// is signed again with a fresh timestamp.
+fn sign_request(key: &str, request: &mut reqwest::Request) -> Result<(), Error> {
+    use hmac::Mac;
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs()
+        .to_string();
+
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key.as_bytes())
+        .map_err(|err| format_err!("Invalid signing key: {}", err))?;
+    mac.update(request.method().as_str().as_bytes());
+    mac.update(request.url().as_str().as_bytes());
+    mac.update(timestamp.as_bytes());
+    let signature = format!("{:x}", mac.finalize().into_bytes());
+
+    let headers = request.headers_mut();
+    headers.insert("X-Wasp-Timestamp", timestamp.parse()?);
+    headers.insert("X-Wasp-Signature", signature.parse()?);
+    Ok(())
+}
+
+/// Appends a request to the `--dump-request` file as one JSON line
+///
+/// The bearer token is a default header of the client, so it's not part of
+/// `request`; credential headers are masked regardless. Bodies are only
+/// described by size and SHA-256 since modules can be large.
+fn dump_request(file: &Path, request: &reqwest::Request) -> Result<(), Error> {
+    use std::io::Write;
+
+    let headers: serde_json::Map<String, JsonValue> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == reqwest::header::AUTHORIZATION
+                || name == reqwest::header::PROXY_AUTHORIZATION
+            {
+                "***".to_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), JsonValue::String(value))
+        })
+        .collect();
+    let body = request.body().map(|body| match body.as_bytes() {
+        Some(bytes) => serde_json::json!({ "size": bytes.len(), "sha256": cache::hash(bytes) }),
+        None => serde_json::json!({ "streamed": true }),
+    });
+
+    let entry = serde_json::json!({
+        "method": request.method().as_str(),
+        "url": request.url().as_str(),
+        "headers": headers,
+        "body": body,
+    });
+
+    let mut output = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?;
+    writeln!(output, "{}", entry)?;
+    Ok(())
+}
+
+/// Whether the keyring refused access because it's locked or its unlock
+/// prompt was dismissed
+fn is_keyring_locked(err: &keyring::KeyringError) -> bool {
+    let message = err.to_string().to_lowercase();
+    [
+        "locked",
+        "prompt",
+        "dismissed",
+        "user canceled",
+        "interaction is not allowed",
+    ]
+    .iter()
+    .any(|needle| message.contains(needle))
+}
+
+/// Header identifying a request when reporting problems to the wasp team
+pub(crate) const REQUEST_ID: &str = "X-Request-Id";
+
+pub(crate) fn new_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Copies the id we sent onto the response unless the server echoed its own
+pub(crate) fn keep_request_id(
+    mut response: reqwest::Response,
+    request_id: Option<reqwest::header::HeaderValue>,
+) -> reqwest::Response {
+    if let Some(request_id) = request_id {
+        response
+            .headers_mut()
+            .entry(REQUEST_ID)
+            .or_insert(request_id);
+    }
+    response
+}
+
+/// Most of an error body that's worth showing; proxies can answer with
+/// arbitrarily large HTML pages. `WASP_MAX_ERROR_BODY` overrides it.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+fn max_error_body() -> usize {
+    parse_max_error_body(std::env::var("WASP_MAX_ERROR_BODY").ok().as_deref())
+}
+
+/// A cap of 0 would hide every error message, so it's ignored like any
+/// other value that isn't a positive number
+fn parse_max_error_body(value: Option<&str>) -> usize {
+    value
+        .and_then(|max| max.trim().parse().ok())
+        .filter(|max| *max > 0)
+        .unwrap_or(MAX_ERROR_BODY)
+}
+
+/// Reads at most `max_error_body()` bytes of an error response
+async fn read_error_body(mut response: reqwest::Response) -> Result<String, Error> {
+    let max = max_error_body();
+    let mut body = Vec::new();
+    let mut truncated = false;
+    while let Some(chunk) = response.chunk().await? {
+        let room = max - body.len();
+        if chunk.len() > room {
+            body.extend_from_slice(&chunk[..room]);
+            truncated = true;
+            break;
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(error_body_text(body, truncated))
+}
+
+/// Decodes an error body read by `read_error_body`, marking a truncated one
+fn error_body_text(mut body: Vec<u8>, truncated: bool) -> String {
+    if truncated {
+        // don't leave half a character at the cut
+        if let Err(err) = std::str::from_utf8(&body) {
+            if err.error_len().is_none() {
+                body.truncate(err.valid_up_to());
+            }
+        }
+    }
+
+    let mut text = String::from_utf8_lossy(&body).into_owned();
+    if truncated {
+        text.push_str("… (truncated)");
+    }
+    text
+}
+
+/// Passes successful responses through and turns any other into an `ApiError`
+///
+/// `step` prefixes the message, e.g. `"Login error: "`.
+pub async fn handle_error(
+    step: &str,
+    response: reqwest::Response,
+) -> Result<reqwest::Response, Error> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct ErrorResponse {
+        error: String,
+    }
+
+    let status = response.status();
+
+    // only reached when --max-redirects stopped following it
+    if status.is_redirection() {
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .unwrap_or("nowhere");
+        return Err(ApiError {
+            status,
+            message: format!(
+                "{}{} answered {} to {}; check --api",
+                step,
+                response.url(),
+                status,
+                location
+            ),
+        }
+        .into());
+    }
+
+    let request_id = response
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_owned);
+    let text = read_error_body(response).await?;
+    let mut message = match serde_json::from_str::<ErrorResponse>(&text) {
+        Ok(err) => format!("{}{}", step, err.error),
+        _ => format!("{}{}", step, text),
+    };
+    if let Some(request_id) = request_id {
+        message = format!("{} (request id {})", message, request_id);
+    }
+
+    Err(ApiError { status, message }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_error_body_drops_a_split_character() {
+        // "é" is two bytes, so cutting after 3 bytes splits the second one
+        let body = "éé".as_bytes()[..3].to_vec();
+        assert_eq!(error_body_text(body, true), "é… (truncated)");
+    }
+
+    #[test]
+    fn error_body_only_cuts_a_trailing_partial_character() {
+        assert_eq!(error_body_text("é".as_bytes().to_vec(), false), "é");
+        // invalid bytes in the middle are replaced rather than cut
+        assert_eq!(
+            error_body_text(b"a\xffb".to_vec(), true),
+            "a\u{fffd}b… (truncated)"
+        );
+    }
+
+    #[test]
+    fn max_error_body_ignores_invalid_and_zero_values() {
+        assert_eq!(parse_max_error_body(None), MAX_ERROR_BODY);
+        assert_eq!(parse_max_error_body(Some("1024")), 1024);
+        assert_eq!(parse_max_error_body(Some("0")), MAX_ERROR_BODY);
+        assert_eq!(parse_max_error_body(Some("-1")), MAX_ERROR_BODY);
+        assert_eq!(parse_max_error_body(Some("64k")), MAX_ERROR_BODY);
+        assert_eq!(parse_max_error_body(Some("")), MAX_ERROR_BODY);
+    }
+}
@@ -0,0 +1,111 @@
+use failure::{format_err, Error};
+use std::{io::ErrorKind, path::PathBuf, sync::OnceLock};
+use toml::{value::Table, Value};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings that can live under `[default]` or a `[profile.<name>]` table
+pub const SETTINGS: &[&str] = &["api", "account", "customer_id"];
+
+/// Whether `config:set` accepts `key` without `--force`
+///
+/// That's `default.<setting>` or `profile.<name>.<setting>`.
+pub fn is_known_key(key: &str) -> bool {
+    let parts: Vec<&str> = key.split('.').collect();
+    match parts.as_slice() {
+        ["default", setting] => SETTINGS.contains(setting),
+        ["profile", name, setting] => !name.is_empty() && SETTINGS.contains(setting),
+        _ => false,
+    }
+}
+
+/// User settings stored in `~/.wasp/config.toml`
+///
+/// The file is kept as a plain TOML table so keys this version doesn't know
+/// about survive a `config:set`.
+#[derive(Debug, Default)]
+pub struct Config {
+    table: Table,
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf, Error> {
+        Ok(crate::wasp_dir()?.join("config.toml"))
+    }
+
+    pub fn load() -> Result<Self, Error> {
+        let path = Self::path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(Self {
+                table: toml::from_str(&contents)
+                    .map_err(|err| format_err!("Invalid config {}: {}", path.display(), err))?,
+            }),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(format_err!("Could not read {}: {}", path.display(), err)),
+        }
+    }
+
+    /// The config as loaded by the first caller, for commands that only read it
+    pub fn shared() -> Result<&'static Self, Error> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let config = Self::load()?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, toml::to_string_pretty(&self.table)?)?;
+        Ok(())
+    }
+
+    /// Looks up a dotted key such as `default.api`
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        let mut parts = key.split('.');
+        let mut value = self.table.get(parts.next()?)?;
+        for part in parts {
+            value = value.as_table()?.get(part)?;
+        }
+        Some(value)
+    }
+
+    /// Looks up a dotted key that holds a string
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Value::as_str)
+    }
+
+    /// Looks up a setting in `[profile.<name>]`, falling back to `[default]`
+    pub fn setting(&self, profile: Option<&str>, name: &str) -> Option<&str> {
+        profile
+            .and_then(|profile| self.get_str(&format!("profile.{}.{}", profile, name)))
+            .or_else(|| self.get_str(&format!("default.{}", name)))
+    }
+
+    pub fn has_profile(&self, profile: &str) -> bool {
+        self.get(&format!("profile.{}", profile))
+            .map_or(false, Value::is_table)
+    }
+
+    /// Sets a dotted key, creating intermediate tables as needed
+    pub fn set(&mut self, key: &str, value: Value) -> Result<(), Error> {
+        let mut parts: Vec<&str> = key.split('.').collect();
+        let last = parts.pop().filter(|part| !part.is_empty());
+        let last = last.ok_or_else(|| format_err!("Invalid config key {:?}", key))?;
+
+        let mut table = &mut self.table;
+        for part in parts {
+            table = table
+                .entry(part.to_owned())
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| format_err!("{:?} in {:?} is not a table", part, key))?;
+        }
+        table.insert(last.to_owned(), value);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use failure::{format_err, Error};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+/// The local key-value store `wasp run` keeps in `--kvs-directory`
+pub struct Kvs {
+    db: sled::Db,
+}
+
+impl Kvs {
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Self, Error> {
+        let directory = directory.as_ref();
+        let db = sled::open(directory)
+            .map_err(|err| format_err!("Could not open KVS {}: {}", directory.display(), err))?;
+        Ok(Self { db })
+    }
+
+    pub fn insert(&self, key: &str, value: &[u8]) -> Result<(), Error> {
+        self.db.insert(key, value)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), Error> {
+        self.db.clear()?;
+        Ok(())
+    }
+
+    /// Every entry in key order, as it would appear in a seed file
+    pub fn entries(&self) -> Result<Vec<(String, SeedValue)>, Error> {
+        self.db
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    SeedValue::from_bytes(value.to_vec()),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// A value in a `--seed-kvs` or `kvs:export` file
+///
+/// Plain strings are stored as is; `{"type":"json","value":...}` stores the
+/// serialized JSON, `{"type":"string","value":"..."}` the string and
+/// `{"type":"bytes","value":[...]}` raw bytes that aren't UTF-8.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum SeedValue {
+    Plain(String),
+    Typed(TypedValue),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum TypedValue {
+    Json(JsonValue),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl SeedValue {
+    pub fn from_bytes(value: Vec<u8>) -> Self {
+        match String::from_utf8(value) {
+            Ok(value) => SeedValue::Plain(value),
+            Err(err) => SeedValue::Typed(TypedValue::Bytes(err.into_bytes())),
+        }
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>, Error> {
+        match self {
+            SeedValue::Plain(value) | SeedValue::Typed(TypedValue::String(value)) => {
+                Ok(value.into_bytes())
+            }
+            SeedValue::Typed(TypedValue::Json(value)) => Ok(serde_json::to_vec(&value)?),
+            SeedValue::Typed(TypedValue::Bytes(value)) => Ok(value),
+        }
+    }
+}
+
+/// One line of an NDJSON export
+#[derive(Debug, Deserialize, Serialize)]
+struct Record {
+    key: String,
+    value: SeedValue,
+}
+
+/// Writes every entry as one JSON object, or as NDJSON records of
+/// `{"key":...,"value":...}` which large stores can stream
+pub fn export<W: Write>(kvs: &Kvs, mut output: W, ndjson: bool) -> Result<usize, Error> {
+    let entries = kvs.entries()?;
+    let count = entries.len();
+
+    if ndjson {
+        for (key, value) in entries {
+            serde_json::to_writer(&mut output, &Record { key, value })?;
+            writeln!(output)?;
+        }
+    } else {
+        let entries: BTreeMap<String, SeedValue> = entries.into_iter().collect();
+        serde_json::to_writer_pretty(&mut output, &entries)?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+
+    Ok(count)
+}
+
+/// Reads an export in either format; files ending in `.ndjson` are NDJSON
+pub fn read_export(file: &str) -> Result<Vec<(String, SeedValue)>, Error> {
+    let open_error = |err| format_err!("Could not open {}: {}", file, err);
+    let invalid = |err| format_err!("Invalid KVS file {}: {}", file, err);
+
+    if file.ends_with(".ndjson") {
+        let reader = std::io::BufReader::new(std::fs::File::open(file).map_err(open_error)?);
+        let mut entries = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record = serde_json::from_str(&line).map_err(invalid)?;
+            entries.push((record.key, record.value));
+        }
+        Ok(entries)
+    } else {
+        let contents = std::fs::read(file).map_err(open_error)?;
+        let entries: BTreeMap<String, SeedValue> =
+            serde_json::from_slice(&contents).map_err(invalid)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+/// Inserts every entry into the store, returning how many keys were written
+pub fn import(kvs: &Kvs, entries: Vec<(String, SeedValue)>) -> Result<usize, Error> {
+    let count = entries.len();
+    for (key, value) in entries {
+        kvs.insert(&key, &value.into_bytes()?)?;
+    }
+    kvs.flush()?;
+    Ok(count)
+}
+
+/// Writes every entry of a JSON seed file into the store, returning how many
+/// keys were inserted
+pub fn seed(kvs: &Kvs, file: &str) -> Result<usize, Error> {
+    let contents = std::fs::read(file)
+        .map_err(|err| format_err!("Could not open seed file {}: {}", file, err))?;
+    let entries: BTreeMap<String, SeedValue> = serde_json::from_slice(&contents)
+        .map_err(|err| format_err!("Invalid seed file {}: {}", file, err))?;
+
+    import(kvs, entries.into_iter().collect())
+}
@@ -0,0 +1,567 @@
+//! Operations on the wasp API behind the `wasp` command
+//!
+//! Everything here returns what the API answered instead of printing it, so
+//! other tools can script wasp; progress is reported through the `log` crate.
+
+use failure::{format_err, Error};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+pub mod cache;
+mod client;
+pub mod logger;
+pub mod vault;
+
+use cache::SharedUploadCache;
+use client::{clock_skew, describe_clock_skew, new_request_id, REQUEST_ID};
+
+pub use client::{handle_error, is_conflict, is_not_found, ApiError, AuthError, Client};
+
+/// Directory holding local wasp state such as the upload cache
+pub fn wasp_dir() -> Result<PathBuf, Error> {
+    dirs::home_dir()
+        .map(|home| home.join(".wasp"))
+        .ok_or_else(|| format_err!("Could not locate the home directory"))
+}
+
+/// Parses `NAME=VALUE` into an env var, expanding `${VAR}` in VALUE
+///
+/// An empty VALUE unsets the variable and a bare `NAME` takes its value from
+/// the process environment. `vault://` references are kept as they are for
+/// `vault::resolve_env`.
+pub fn parse_env(input: &str) -> Result<(String, JsonValue), String> {
+    let mut parts = input.split('=');
+    let name = parts
+        .next()
+        .ok_or_else(|| "Invalid env".to_owned())?
+        .to_owned();
+    let value = if let Some(v) = parts.next() {
+        if v.is_empty() {
+            JsonValue::Null
+        } else if vault::is_reference(v) {
+            vault::SecretRef::parse(v)?;
+            JsonValue::String(v.to_owned())
+        } else {
+            JsonValue::String(interpolate(v)?)
+        }
+    } else {
+        JsonValue::String(std::env::var(&name).map_err(|_| format!("{} not found", &name))?)
+    };
+    Ok((name, value))
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` from the process environment
+fn interpolate(input: &str) -> Result<String, String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed ${{ in {:?}", input))?;
+        let reference = &rest[start + 2..start + end];
+
+        let (name, default) = match reference.find(":-") {
+            Some(index) => (&reference[..index], Some(&reference[index + 2..])),
+            None => (reference, None),
+        };
+
+        match (std::env::var(name), default) {
+            Ok(value) => output.push_str(&value),
+            Err(_) => match default {
+                Some(default) => output.push_str(default),
+                None => return Err(format!("{} not found", name)),
+            },
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Logs in with a username and password, storing the token in the keyring
+///
+/// `expires_in` asks for a token that expires sooner than the server's
+/// default. Returns the seconds until the stored token expires.
+pub async fn login(
+    client: &Client,
+    username: String,
+    password: String,
+    expires_in: Option<u64>,
+) -> Result<u64, Error> {
+    // there's no token yet, so don't go through `Client::post`
+    let request_id = new_request_id();
+    let response = client
+        .send_with_retry(|| async {
+            let mut request = client
+                .api_builder()?
+                .build()?
+                .post(&client.url("/login"))
+                .header(REQUEST_ID, request_id.as_str())
+                .basic_auth(&username, Some(&password));
+            if let Some(expires_in) = expires_in {
+                request = request.query(&[("expires_in", expires_in)]);
+            }
+            Ok::<_, Error>(request)
+        })
+        .await?;
+
+    let response = handle_error("Login error: ", response).await?;
+
+    // the server's clock decides when the token expires, so a local clock
+    // that's off makes tokens look expired too early or too late
+    let skew = response
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .map(clock_skew);
+    if let Some(description) = skew.and_then(describe_clock_skew) {
+        warn!(
+            "warning: {}; tokens may appear expired early. Check that NTP is keeping it in sync.",
+            description
+        );
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct LoginResponse {
+        access_token: String,
+        expires_in: u64,
+    }
+
+    let res: LoginResponse = response.json().await?;
+
+    // a server that ignores `expires_in` may hand out a longer-lived token;
+    // forget it locally when asked to, even if the server would still take it
+    let expires_in = match expires_in {
+        Some(requested) => requested.min(res.expires_in),
+        None => res.expires_in,
+    };
+    client.set(res.access_token, expires_in, skew)?;
+
+    Ok(expires_in)
+}
+
+/// Fetches a host as `host:get` shows it
+pub async fn view(client: &Client, host: &str) -> Result<JsonValue, Error> {
+    let response = client
+        .send_with_retry(|| client.get(format!("/hosts/{}", host)))
+        .await?;
+
+    let response = handle_error("", response).await?;
+
+    Ok(response.json().await?)
+}
+
+/// The body of `POST /hosts`
+#[derive(Debug, Serialize)]
+pub struct CreateBody {
+    pub host: String,
+    pub customer_id: String,
+
+    #[serde(flatten)]
+    pub configuration: ConfigureBody,
+}
+
+impl CreateBody {
+    /// The body as it's logged, with secret env values masked
+    pub fn redacted(&self) -> Result<JsonValue, Error> {
+        Ok(redact_env(
+            serde_json::to_value(self)?,
+            &self.configuration.secret_keys,
+        ))
+    }
+}
+
+/// Creates a host
+pub async fn create(client: &Client, body: &CreateBody) -> Result<(), Error> {
+    debug!("{:#}", body.redacted()?);
+
+    let key = idempotency_key(
+        &body.host,
+        body,
+        body.configuration.idempotency_key.as_deref(),
+    )?;
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(
+                client
+                    .post("/hosts")
+                    .await?
+                    .header("Idempotency-Key", key.as_str())
+                    .json(body),
+            )
+        })
+        .await?;
+
+    handle_error("", response).await?;
+
+    Ok(())
+}
+
+/// The body of `POST /hosts/{host}`; unset fields are left unchanged
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConfigureBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, JsonValue>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<JsonValue>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub log_drains: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollout_percent: Option<u8>,
+
+    /// Directory of static files served alongside the module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cdn_directory: Option<String>,
+
+    /// 5-field cron expression the entry function also runs on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+
+    /// IANA timezone `schedule` is evaluated in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule_tz: Option<String>,
+
+    /// Env vars masked by `redacted`
+    #[serde(skip)]
+    pub secret_keys: BTreeSet<String>,
+
+    /// Overrides the Idempotency-Key derived from the body
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
+}
+
+impl ConfigureBody {
+    /// The body as it's logged, with secret env values masked
+    pub fn redacted(&self) -> Result<JsonValue, Error> {
+        Ok(redact_env(serde_json::to_value(self)?, &self.secret_keys))
+    }
+}
+
+/// Replaces the values of secret env vars in a serialized body with `***`
+fn redact_env(mut body: JsonValue, secret_keys: &BTreeSet<String>) -> JsonValue {
+    if let Some(env) = body.get_mut("env").and_then(JsonValue::as_object_mut) {
+        for (name, value) in env.iter_mut() {
+            if secret_keys.contains(name) {
+                *value = JsonValue::String("***".to_owned());
+            }
+        }
+    }
+    body
+}
+
+/// Updates a host
+pub async fn configure(client: &Client, host: &str, body: &ConfigureBody) -> Result<(), Error> {
+    debug!("{:#}", body.redacted()?);
+
+    let key = idempotency_key(host, body, body.idempotency_key.as_deref())?;
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(
+                client
+                    .post(format!("/hosts/{}", host))
+                    .await?
+                    .header("Idempotency-Key", key.as_str())
+                    .json(body),
+            )
+        })
+        .await?;
+
+    handle_error("", response).await?;
+
+    Ok(())
+}
+
+/// The Idempotency-Key for writing `body` to `host`
+///
+/// Unless overridden it's a hash of both, so retries of the same change are
+/// deduplicated by the server while any other change gets a new key.
+fn idempotency_key<T: Serialize>(
+    host: &str,
+    body: &T,
+    explicit: Option<&str>,
+) -> Result<String, Error> {
+    if let Some(key) = explicit {
+        return Ok(key.to_owned());
+    }
+    // JSON objects serialize with sorted keys, so the hash doesn't depend on
+    // HashMap iteration order
+    let body = serde_json::to_value(body)?;
+    Ok(cache::hash(format!("{}\n{}", host, body).as_bytes()))
+}
+
+/// Looks up the id of the module `tag` points at
+pub async fn resolve_tag(client: &Client, tag: &str) -> Result<String, Error> {
+    let response = client
+        .send_with_retry(|| client.get(format!("/modules/tags/{}", tag)))
+        .await?;
+    let response = handle_error(&format!("Could not resolve tag {}: ", tag), response).await?;
+    let body: JsonValue = response.json().await?;
+
+    let module_id = body["module_id"]
+        .as_str()
+        .ok_or_else(|| format_err!("Tag {} does not point at a module: {}", tag, body))?;
+    debug!("Tag {} points at {}", tag, module_id);
+
+    Ok(module_id.to_owned())
+}
+
+/// A module the server has accepted
+#[derive(Debug, Serialize)]
+pub struct UploadedModule {
+    pub module_id: String,
+    pub size: u64,
+    pub compile_ms: u64,
+    /// Exported functions, once `verify_module` has seen the module compiled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functions: Option<Vec<String>>,
+}
+
+const VERIFY_ATTEMPTS: u32 = 30;
+const VERIFY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Waits for the server to finish compiling a module, returning its exported
+/// functions
+///
+/// Modules are `"compiled"` once usable and `"error"` or `"failed"` when
+/// compilation failed; any other status means it's still queued or building.
+pub async fn verify_module(client: &Client, module_id: &str) -> Result<Vec<String>, Error> {
+    let path = format!("/modules/{}", module_id);
+
+    for attempt in 1..=VERIFY_ATTEMPTS {
+        let response = client.send_with_retry(|| client.get(&path)).await?;
+        let module: JsonValue = handle_error("", response).await?.json().await?;
+
+        match module["status"].as_str().unwrap_or("unknown") {
+            "compiled" => {
+                let functions = module["functions"]
+                    .as_array()
+                    .map(|functions| {
+                        functions
+                            .iter()
+                            .filter_map(JsonValue::as_str)
+                            .map(str::to_owned)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                return Ok(functions);
+            }
+            "error" | "failed" => {
+                return Err(format_err!(
+                    "Module {} failed to compile: {}",
+                    module_id,
+                    module["error"].as_str().unwrap_or("no details given")
+                ));
+            }
+            status => {
+                debug!(
+                    "Module {} is {} ({}/{})",
+                    module_id, status, attempt, VERIFY_ATTEMPTS
+                );
+                tokio::time::sleep(VERIFY_INTERVAL).await;
+            }
+        }
+    }
+
+    Err(format_err!(
+        "Module {} was not compiled after {}s",
+        module_id,
+        VERIFY_ATTEMPTS as u64 * VERIFY_INTERVAL.as_secs()
+    ))
+}
+
+/// Uploads a module from a file or an http(s) URL
+pub async fn upload(client: &Client, module_path: &str) -> Result<UploadedModule, Error> {
+    let body = read_module(client, module_path).await?;
+    upload_bytes(client, module_path, body).await
+}
+
+pub fn is_url(module: &str) -> bool {
+    module.starts_with("http://") || module.starts_with("https://")
+}
+
+/// Reads a module from disk or, for http(s) URLs, downloads it
+pub async fn read_module(client: &Client, module_path: &str) -> Result<Vec<u8>, Error> {
+    if !is_url(module_path) {
+        return Ok(tokio::fs::read(module_path).await?);
+    }
+
+    info!("Downloading module: {}", module_path);
+
+    // no Authorization header here; the wasp token is not meant for other servers
+    let response = client.builder().build()?.get(module_path).send().await?;
+    let response = handle_error(&format!("Could not download {}: ", module_path), response).await?;
+    let body = response.bytes().await?.to_vec();
+
+    if !body.starts_with(b"\0asm") {
+        return Err(format_err!("{} is not a WASM module", module_path));
+    }
+
+    Ok(body)
+}
+
+pub async fn upload_bytes(
+    client: &Client,
+    module_path: &str,
+    body: Vec<u8>,
+) -> Result<UploadedModule, Error> {
+    info!("Uploading module: {:?}", module_path);
+    let size = body.len() as u64;
+    let started = Instant::now();
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(client.post("/compile").await?.body(body.clone()))
+        })
+        .await?;
+
+    let response = handle_error("", response).await?;
+
+    #[derive(Debug, Deserialize)]
+    struct LoginResponse {
+        #[serde(rename = "ok")]
+        module_id: String,
+    }
+
+    let res: LoginResponse = response.json().await?;
+    let compile_ms = started.elapsed().as_millis() as u64;
+
+    info!("Uploaded {} bytes, compiled in {}ms", size, compile_ms);
+
+    Ok(UploadedModule {
+        module_id: res.module_id,
+        size,
+        compile_ms,
+        functions: None,
+    })
+}
+
+/// Uploads a module unless the cache already has an id for identical contents
+pub async fn cached_upload(
+    client: &Client,
+    cache: &SharedUploadCache,
+    module_path: &str,
+) -> Result<String, Error> {
+    let body = read_module(client, module_path).await?;
+    let hash = cache::hash(&body);
+
+    if let Some(module_id) = cache.get(client.service(), &hash) {
+        info!("Reusing module {} for {:?}", module_id, module_path);
+        return Ok(module_id);
+    }
+
+    cache
+        .get_or_upload(client.service(), &hash, || async {
+            Ok(upload_bytes(client, module_path, body).await?.module_id)
+        })
+        .await
+}
+
+/// Adds `tag` to a module with `PUT /modules/{id}/tags`
+pub async fn add_module_tag(client: &Client, module_id: &str, tag: &str) -> Result<(), Error> {
+    let body = serde_json::json!({ "tag": tag });
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(
+                client
+                    .put(format!("/modules/{}/tags", module_id))
+                    .await?
+                    .json(&body),
+            )
+        })
+        .await?;
+
+    handle_error("", response).await?;
+
+    Ok(())
+}
+
+/// Points `tag` at a module
+pub async fn tag_module(client: &Client, tag: &str, module_id: &str) -> Result<(), Error> {
+    let body = serde_json::json!({ "module_id": module_id });
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(
+                client
+                    .put(format!("/modules/tags/{}", tag))
+                    .await?
+                    .json(&body),
+            )
+        })
+        .await?;
+
+    handle_error("", response).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_expands_set_variables() {
+        std::env::set_var("WASP_TEST_INTERPOLATE_SET", "value");
+        assert_eq!(
+            interpolate("pre_${WASP_TEST_INTERPOLATE_SET}_post"),
+            Ok("pre_value_post".to_owned())
+        );
+        assert_eq!(
+            interpolate("${WASP_TEST_INTERPOLATE_SET:-default}"),
+            Ok("value".to_owned())
+        );
+    }
+
+    #[test]
+    fn interpolate_falls_back_to_the_default() {
+        assert_eq!(
+            interpolate("${WASP_TEST_INTERPOLATE_UNSET:-fallback}"),
+            Ok("fallback".to_owned())
+        );
+        assert_eq!(
+            interpolate("${WASP_TEST_INTERPOLATE_UNSET:-}"),
+            Ok(String::new())
+        );
+    }
+
+    #[test]
+    fn interpolate_names_a_missing_variable() {
+        assert_eq!(
+            interpolate("${WASP_TEST_INTERPOLATE_UNSET}"),
+            Err("WASP_TEST_INTERPOLATE_UNSET not found".to_owned())
+        );
+    }
+
+    #[test]
+    fn interpolate_rejects_an_unclosed_reference() {
+        assert_eq!(
+            interpolate("a${WASP_TEST_INTERPOLATE_SET"),
+            Err("Unclosed ${ in \"a${WASP_TEST_INTERPOLATE_SET\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn interpolate_keeps_a_literal_dollar() {
+        assert_eq!(interpolate("$5 and $HOME"), Ok("$5 and $HOME".to_owned()));
+        assert_eq!(interpolate("cost$"), Ok("cost$".to_owned()));
+    }
+}
@@ -0,0 +1,123 @@
+use failure::Error;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value as JsonValue};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+
+/// Writes the CLI's own log records to stderr and, with `--log-file`, as JSON
+/// lines to a file
+struct Logger {
+    stderr_level: LevelFilter,
+    file: Option<Mutex<File>>,
+    command: String,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Logger {
+    fn write_entry(&self, level: Level, fields: JsonValue) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut entry = json!({
+            "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            "level": level.to_string(),
+            "command": self.command,
+        });
+        if let (Some(entry), JsonValue::Object(fields)) = (entry.as_object_mut(), fields) {
+            entry.extend(fields);
+        }
+
+        // a failing log file shouldn't fail the command itself
+        if let Ok(mut file) = file.lock() {
+            let _ = writeln!(file, "{}", entry);
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // keep dependencies like hyper quiet, even with --verbose
+        metadata.level() <= log::max_level() && metadata.target().starts_with("wasp")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if record.level() <= self.stderr_level {
+            eprintln!("{}", record.args());
+        }
+
+        self.write_entry(
+            record.level(),
+            json!({ "message": record.args().to_string() }),
+        );
+    }
+
+    fn flush(&self) {
+        if let Some(Ok(mut file)) = self.file.as_ref().map(Mutex::lock) {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Installs the logger; `log_file` is appended to so runs accumulate
+pub fn init(
+    verbose: bool,
+    quiet: bool,
+    log_file: Option<&Path>,
+    command: &str,
+) -> Result<(), Error> {
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+
+    let stderr_level = if verbose {
+        LevelFilter::Debug
+    } else if quiet {
+        LevelFilter::Error
+    } else {
+        LevelFilter::Info
+    };
+
+    // the file gets everything, independently of --verbose
+    let max_level = if file.is_some() {
+        LevelFilter::Debug
+    } else {
+        stderr_level
+    };
+
+    let logger = LOGGER.get_or_init(|| Logger {
+        stderr_level,
+        file,
+        command: command.to_owned(),
+    });
+    let _ = log::set_logger(logger);
+    log::set_max_level(max_level);
+
+    Ok(())
+}
+
+/// Records the outcome of an API request in the log file
+///
+/// `status` is `None` when no response was received.
+pub fn request(method: &str, url: &str, status: Option<u16>) {
+    if let Some(logger) = LOGGER.get() {
+        logger.write_entry(
+            Level::Info,
+            json!({ "method": method, "url": url, "status": status }),
+        );
+    }
+}
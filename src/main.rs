@@ -1,50 +1,197 @@
-use failure::{format_err, Error};
-use serde_derive::{Deserialize, Serialize};
+use base64::Engine;
+use failure::{format_err, Error, Fail};
+use futures::{
+    future::BoxFuture,
+    stream::{self, StreamExt},
+    FutureExt,
+};
+use log::{debug, info, warn};
+use regex::Regex;
+use serde_derive::Deserialize;
 use serde_json::Value as JsonValue;
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::{TryFrom, TryInto},
     env::set_var,
+    io::IsTerminal,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 use structopt::{clap::AppSettings, StructOpt};
 use wasp_app_route::start;
 
+mod config;
+mod kvs;
+mod table;
+
+use config::Config;
+use kvs::Kvs;
+use wasp::{
+    add_module_tag,
+    cache::{self, SharedUploadCache, UploadCache},
+    cached_upload, handle_error, is_conflict, is_not_found, is_url, logger, parse_env, resolve_tag,
+    tag_module, verify_module, wasp_dir, ApiError, AuthError, Client, ConfigureBody, CreateBody,
+};
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "wasp",
-    raw(global_settings = "&[AppSettings::ColoredHelp, AppSettings::VersionlessSubcommands]")
+    raw(global_settings = "&[AppSettings::ColoredHelp, AppSettings::VersionlessSubcommands]"),
+    raw(after_help = "EXIT_CODES_HELP")
 )]
+struct Cli {
+    /// Print requests and other diagnostics to stderr
+    #[structopt(short = "v", long = "verbose", raw(global = "true"))]
+    verbose: bool,
+
+    /// Only print errors to stderr; stdout is unaffected
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        conflicts_with = "verbose",
+        raw(global = "true", visible_alias = r#""silent""#)
+    )]
+    quiet: bool,
+
+    /// Append a JSON line per log record and API request to this file
+    #[structopt(long = "log-file", parse(from_os_str), raw(global = "true"))]
+    log_file: Option<PathBuf>,
+
+    /// Directory of the upload cache [default: ~/.wasp]
+    #[structopt(
+        long = "module-cache-dir",
+        env = "WASP_CACHE_DIR",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    module_cache_dir: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Opt,
+}
+
+#[derive(Debug, StructOpt)]
 enum Opt {
     /// Run a wasp module locally
     #[structopt(name = "run")]
     Run {
-        #[structopt(name = "MODULE")]
-        module: String,
+        #[structopt(flatten)]
+        options: RunOpts,
+    },
+
+    /// Upload a WASM module
+    #[structopt(name = "upload")]
+    Upload {
+        /// Module file, or an http(s) URL to download it from; with several,
+        /// unchanged modules are skipped and each prints its path and module id
+        #[structopt(name = "MODULE_PATH", raw(required = "true"))]
+        modules: Vec<String>,
 
-        #[structopt(short = "f", long = "function", default_value = "run")]
-        function: String,
+        /// Wait until the server reports the module compiled and print its functions
+        #[structopt(long = "verify")]
+        verify: bool,
 
-        #[structopt(short = "p", long = "port", default_value = "5000")]
-        port: usize,
+        /// Stop at the first failed upload instead of trying the remaining modules
+        #[structopt(long = "fail-fast")]
+        fail_fast: bool,
 
-        #[structopt(short = "e", long = "env-file")]
-        env_file: Option<String>,
+        /// Point TAG at the uploaded module (repeatable)
+        #[structopt(long = "tag", name = "TAG")]
+        tags: Vec<String>,
+
+        #[structopt(flatten)]
+        output: OutputOpts,
 
-        #[structopt(short = "c", long = "cdn-directory")]
-        cdn_directory: Option<String>,
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-        #[structopt(short = "P", long = "protected-cdn-directory")]
-        protected_cdn_directory: Option<String>,
+    /// Drop upload cache entries for deleted or old modules
+    #[structopt(name = "upload:gc")]
+    UploadGc {
+        /// Also drop entries uploaded longer ago than this, e.g. 30days
+        #[structopt(long = "max-age", parse(try_from_str = "humantime::parse_duration"))]
+        max_age: Option<Duration>,
 
-        #[structopt(short = "k", long = "kvs-directory", default_value = ".db")]
-        kvs_directory: String,
+        #[structopt(flatten)]
+        source: SourceOpts,
     },
 
-    /// Upload a WASM module
-    #[structopt(name = "upload")]
-    Upload {
-        #[structopt(name = "MODULE_PATH")]
-        module: String,
+    /// Upload every WASM module in a directory
+    #[structopt(name = "module:upload-many")]
+    UploadMany {
+        #[structopt(name = "DIR")]
+        directory: String,
+
+        /// Also upload modules in subdirectories
+        #[structopt(short = "r", long = "recursive")]
+        recursive: bool,
+
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
+
+    /// Compile a WAT file to a WASM module
+    #[structopt(name = "module:compile")]
+    Compile {
+        #[structopt(name = "INPUT")]
+        input: String,
+
+        /// Where to write the module; INPUT with a .wasm extension by default
+        #[structopt(short = "o", long = "output")]
+        output: Option<String>,
+
+        /// Validate the compiled module before writing it
+        #[structopt(long = "validate")]
+        validate: bool,
+    },
+
+    /// Show the exports and imports that differ between two uploaded modules
+    #[structopt(name = "module:diff")]
+    ModuleDiff {
+        #[structopt(name = "MODULE_A")]
+        a: String,
+
+        #[structopt(name = "MODULE_B")]
+        b: String,
+
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
+
+    /// Print the SHA-256 of a module file, like sha256sum
+    #[structopt(name = "module:checksum")]
+    Checksum {
+        #[structopt(name = "FILE")]
+        file: String,
+    },
+
+    /// List uploaded modules
+    #[structopt(name = "module:list")]
+    ModuleList {
+        #[structopt(flatten)]
+        pages: PageOpts,
+
+        #[structopt(flatten)]
+        output: OutputOpts,
+
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
+
+    /// Point a module tag at another module
+    #[structopt(name = "module:retag")]
+    ModuleRetag {
+        #[structopt(name = "TAG")]
+        tag: String,
+
+        #[structopt(name = "MODULE_ID")]
+        module_id: String,
 
         #[structopt(flatten)]
         source: SourceOpts,
@@ -53,11 +200,21 @@ enum Opt {
     /// Create a host
     #[structopt(name = "host:create")]
     Create {
+        /// Host name, or NAME@CUSTOMER_ID to give the customer too
         #[structopt(name = "HOST")]
-        host: String,
+        host: Option<String>,
 
+        /// Defaults to `customer_id` from the --profile or [default] config
         #[structopt(name = "CUSTOMER_ID")]
-        customer_id: String,
+        customer_id: Option<String>,
+
+        /// Read CUSTOMER_ID from the first line of FILE
+        #[structopt(
+            long = "customer-id-file",
+            name = "FILE",
+            conflicts_with = "CUSTOMER_ID"
+        )]
+        customer_id_file: Option<PathBuf>,
 
         #[structopt(flatten)]
         configuration: ConfigureOpts,
@@ -70,7 +227,15 @@ enum Opt {
     #[structopt(name = "host:update")]
     Configure {
         #[structopt(name = "HOST")]
-        host: String,
+        host: Option<String>,
+
+        /// Merge --env into the host's current env instead of replacing it
+        #[structopt(long = "patch")]
+        patch: bool,
+
+        /// Remove an env var from the host's current env (repeatable; implies --patch)
+        #[structopt(long = "unset")]
+        unset: Vec<String>,
 
         #[structopt(flatten)]
         configuration: ConfigureOpts,
@@ -79,405 +244,4194 @@ enum Opt {
         source: SourceOpts,
     },
 
-    /// View a host
-    #[structopt(name = "host:get")]
-    View {
-        #[structopt(name = "HOST")]
-        host: String,
+    /// Configure several hosts at once
+    #[structopt(name = "host:batch-update")]
+    BatchConfigure {
+        #[structopt(name = "HOST", raw(required = "true"))]
+        hosts: Vec<String>,
+
+        /// Number of hosts to update in parallel (at most 16)
+        #[structopt(
+            long = "concurrency",
+            default_value = "4",
+            parse(try_from_str = "parse_concurrency")
+        )]
+        concurrency: usize,
+
+        #[structopt(flatten)]
+        configuration: ConfigureOpts,
 
         #[structopt(flatten)]
         source: SourceOpts,
     },
 
-    /// Login to wasp
-    #[structopt(name = "login")]
-    Login {
-        #[structopt(name = "USERNAME")]
-        username: String,
+    /// List hosts
+    #[structopt(name = "host:list")]
+    HostList {
+        /// Only list hosts running this module
+        #[structopt(long = "filter-module", name = "MODULE_ID")]
+        filter_module: Option<String>,
+
+        #[structopt(flatten)]
+        pages: PageOpts,
+
+        #[structopt(flatten)]
+        output: OutputOpts,
 
         #[structopt(flatten)]
         source: SourceOpts,
     },
 
-    /// Remove local wasp credentials
-    #[structopt(name = "logout")]
-    Logout {
+    /// View a host
+    #[structopt(name = "host:get")]
+    View {
+        #[structopt(name = "HOST")]
+        host: String,
+
+        /// Also fetch a referenced object and print it under NAME_details (repeatable; e.g. module)
+        #[structopt(long = "expand", name = "NAME")]
+        expand: Vec<String>,
+
+        /// Only print this field, e.g. env.DATABASE_URL; strings are printed unquoted
+        #[structopt(long = "field", name = "KEY")]
+        field: Option<String>,
+
+        /// Media type to ask the API for; responses that aren't JSON are printed as is
+        #[structopt(long = "accept", name = "MIME", default_value = "application/json")]
+        accept: String,
+
+        #[structopt(flatten)]
+        output: OutputOpts,
+
         #[structopt(flatten)]
         source: SourceOpts,
     },
-}
 
-#[derive(Debug, StructOpt)]
-struct ConfigureOpts {
-    #[structopt(short = "m", long = "module")]
-    module: Option<String>,
+    /// Move a host to another customer
+    #[structopt(name = "host:transfer")]
+    Transfer {
+        #[structopt(name = "HOST")]
+        host: String,
 
-    #[structopt(short = "f", long = "function")]
-    function: Option<String>,
+        #[structopt(name = "NEW_CUSTOMER_ID")]
+        new_customer_id: String,
 
-    // TODO args
-    // TODO log drains
-    #[structopt(short = "e", long = "env", parse(try_from_str = "parse_env"))]
-    env: Vec<(String, JsonValue)>,
-}
+        /// Required, as the host stops belonging to its current customer
+        #[structopt(long = "confirm")]
+        confirm: bool,
 
-#[derive(Debug, StructOpt)]
-struct SourceOpts {
-    #[structopt(short = "a", long = "api", default_value = "https://api.wasp.ws")]
-    api: String,
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-    #[structopt(short = "A", long = "account", default_value = "default")]
-    account: String,
-}
+    /// Show how traffic is split during a rollout
+    #[structopt(name = "host:rollout")]
+    Rollout {
+        #[structopt(name = "HOST")]
+        host: String,
 
-fn parse_env(input: &str) -> Result<(String, JsonValue), String> {
-    let mut parts = input.split('=');
-    let name = parts
-        .next()
-        .ok_or_else(|| "Invalid env".to_owned())?
-        .to_owned();
-    let value = if let Some(v) = parts.next() {
-        if v.is_empty() {
-            JsonValue::Null
-        } else {
-            JsonValue::String(v.to_owned())
-        }
-    } else {
-        JsonValue::String(std::env::var(&name).map_err(|_| format!("{} not found", &name))?)
-    };
-    Ok((name, value))
-}
+        #[structopt(flatten)]
+        output: OutputOpts,
 
-fn main() {
-    let _ = match Opt::from_args() {
-        Opt::Run {
-            module,
-            function,
-            port,
-            env_file,
-            cdn_directory,
-            protected_cdn_directory,
-            kvs_directory,
-        } => run(
-            module,
-            function,
-            port,
-            env_file,
-            cdn_directory,
-            protected_cdn_directory,
-            kvs_directory,
-        ),
-        Opt::Upload { source, module } => upload(source.into(), module),
-        Opt::Create {
-            source,
-            host,
-            customer_id,
-            configuration,
-        } => create(source.into(), host, customer_id, configuration),
-        Opt::Configure {
-            source,
-            host,
-            configuration,
-        } => configure(source.into(), host, configuration),
-        Opt::View { source, host } => view(source.into(), host),
-        Opt::Login { source, username } => login(source, username),
-        Opt::Logout { source } => logout(source.into()),
-    }
-    .map_err(|err| {
-        eprintln!("{}", err);
-        std::process::exit(1);
-    });
-}
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-fn run(
-    module: String,
-    function: String,
-    port: usize,
-    env_file: Option<String>,
-    cdn_directory: Option<String>,
-    protected_cdn_directory: Option<String>,
-    kvs_directory: String,
-) -> Result<(), Error> {
-    if let Some(file) = env_file {
-        dotenv::from_filename(file).expect("Could not load env file");
-    }
+    /// Show the cron schedule of a host, or remove it with --clear
+    #[structopt(name = "host:schedule")]
+    Schedule {
+        #[structopt(name = "HOST")]
+        host: String,
 
-    set_var("WASP_PLATFORM_FILE", module);
-    set_var("WASP_PLATFORM_ENTRY_FUNCTION", function);
-    set_var("WASP_PLATFORM_KVS_DIR", kvs_directory);
-    set_var("PORT", port.to_string());
+        /// Stop running the host on a schedule
+        #[structopt(long = "clear")]
+        clear: bool,
 
-    if let Some(dir) = cdn_directory {
-        set_var("WASP_CDN_DIRECTORY", dir);
-    }
+        #[structopt(flatten)]
+        output: OutputOpts,
 
-    if let Some(dir) = protected_cdn_directory {
-        set_var("WASP_PROTECTED_CDN_DIRECTORY", dir);
-    }
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-    start();
-    Ok(())
-}
+    /// Print the logs of a host
+    #[structopt(name = "host:logs")]
+    Logs {
+        #[structopt(name = "HOST")]
+        host: String,
 
-fn login(source: SourceOpts, username: String) -> Result<(), Error> {
-    let password = rpassword::prompt_password_stderr("Password: ").unwrap();
+        /// Only print lines matching PATTERN, a regex or plain substring
+        #[structopt(long = "filter", name = "PATTERN")]
+        filter: Option<String>,
 
-    let mut response = reqwest::Client::builder()
-        .timeout(None)
-        .build()?
-        .post(&format!("{}/login", source.api))
-        .basic_auth(username, Some(password))
-        .send()?;
+        /// Only print lines that don't match --filter
+        // -v is taken by the global --verbose
+        #[structopt(long = "invert-match", requires = "PATTERN")]
+        invert_match: bool,
 
-    handle_error("Login error: ", &mut response)?;
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-    #[derive(Debug, Deserialize)]
-    struct LoginResponse {
-        access_token: String,
-        expires_in: u64,
-    }
+    /// Print the environment of a host
+    #[structopt(name = "host:env:get")]
+    EnvGet {
+        #[structopt(name = "HOST")]
+        host: String,
 
-    let res: LoginResponse = response.json()?;
-    let keyring: Client = source.into();
-    keyring.set(res.access_token, res.expires_in)?;
+        #[structopt(name = "KEY")]
+        key: Option<String>,
 
-    eprintln!("Ok");
+        /// Print values the server marks as secret instead of redacting them
+        #[structopt(long = "show-secrets")]
+        show_secrets: bool,
 
-    Ok(())
-}
+        #[structopt(flatten)]
+        filter: EnvFilterOpts,
 
-fn logout(keyring: Client) -> Result<(), Error> {
-    keyring.delete()?;
+        #[structopt(flatten)]
+        output: OutputOpts,
 
-    Ok(())
-}
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-struct Client {
-    service: String,
-    account: String,
-}
+    /// List the env var names of a host
+    #[structopt(name = "host:env:list")]
+    EnvList {
+        #[structopt(name = "HOST")]
+        host: String,
 
-impl Client {
-    pub fn new(service: String, account: String) -> Self {
-        Self { service, account }
-    }
+        /// Print KEY=VALUE pairs instead of only the names
+        #[structopt(long = "show-values")]
+        show_values: bool,
 
-    fn keyring(&self) -> keyring::Keyring {
-        keyring::Keyring::new(&self.service, &self.account)
-    }
+        /// With --show-values, don't mask values of keys that look secret
+        #[structopt(long = "unmask", requires = "show_values")]
+        unmask: bool,
 
-    pub fn set(&self, access_token: String, expires_in: u64) -> Result<(), Error> {
-        self.keyring()
-            .set_password(&serde_json::to_string(&KeyringEntry {
-                access_token,
-                expires_at: SystemTime::now() + Duration::from_secs(expires_in),
-            })?)
-            .map_err(|err| format_err!("{}", err))?;
-        Ok(())
-    }
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-    pub fn get_password(&self) -> Result<String, Error> {
-        let entry = self.keyring().get_password().map_err(|err| match err {
-            keyring::KeyringError::NoPasswordFound if self.account == "default" => {
-                format_err!("No account found. Log in with `wasp login USERNAME`.")
-            }
-            keyring::KeyringError::NoPasswordFound => format_err!(
-                "No account found. Log in with `wasp login USERNAME --account {}`.",
-                self.account
-            ),
-            _ => format_err!("{}", err),
-        })?;
-        let entry: KeyringEntry = serde_json::from_str(&entry)?;
+    /// Copy env vars from one host to another
+    #[structopt(name = "host:copy-env")]
+    CopyEnv {
+        #[structopt(name = "FROM")]
+        from: String,
 
-        if entry.expires_at < SystemTime::now() {
-            return Err(format_err!(
-                "Login token is expired. Log in again with `wasp login`."
-            ));
-        }
+        #[structopt(name = "TO")]
+        to: String,
 
-        Ok(entry.access_token)
-    }
+        /// Only copy these env vars (repeatable)
+        #[structopt(long = "keys")]
+        keys: Vec<String>,
 
-    pub fn delete(&self) -> Result<(), Error> {
-        self.keyring()
-            .delete_password()
-            .map_err(|err| format_err!("{}", err))?;
-        Ok(())
-    }
+        /// Keep env vars on TO that aren't copied instead of replacing its env
+        #[structopt(long = "merge")]
+        merge: bool,
 
-    pub fn client(&self) -> Result<reqwest::Client, Error> {
-        let access_token = self.get_password()?;
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-        let client = reqwest::Client::builder()
-            .timeout(None)
-            .gzip(true)
-            .default_headers({
-                use reqwest::header::HeaderMap;
-                let mut headers = HeaderMap::new();
-                headers.insert("Authorization", format!("Bearer {}", access_token).parse()?);
-                headers
-            })
-            .build()?;
+    /// Print the status of a host
+    #[structopt(name = "host:status")]
+    Status {
+        #[structopt(name = "HOST")]
+        host: String,
 
-        Ok(client)
-    }
+        /// Refresh every N seconds (at least 1) until the host is ready
+        #[structopt(
+            short = "w",
+            long = "watch",
+            parse(try_from_str = "parse_watch_interval")
+        )]
+        watch: Option<u64>,
 
-    pub fn url<T: std::fmt::Display>(&self, path: T) -> String {
-        format!("{}{}", self.service, path)
-    }
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-    pub fn get<T: std::fmt::Display>(&self, path: T) -> Result<reqwest::RequestBuilder, Error> {
-        Ok(self.client()?.get(&self.url(path)))
-    }
+    /// Create or update every host spec in a directory
+    #[structopt(name = "apply")]
+    Apply {
+        #[structopt(name = "DIR")]
+        dir: String,
 
-    pub fn post<T: std::fmt::Display>(&self, path: T) -> Result<reqwest::RequestBuilder, Error> {
-        Ok(self.client()?.post(&self.url(path)))
-    }
-}
+        /// Stop starting new hosts once one fails
+        #[structopt(long = "fail-fast")]
+        fail_fast: bool,
 
-impl From<SourceOpts> for Client {
-    fn from(source: SourceOpts) -> Self {
-        Self::new(source.api, source.account)
-    }
-}
+        /// Number of hosts to apply in parallel (at most 16)
+        #[structopt(
+            long = "concurrency",
+            default_value = "4",
+            parse(try_from_str = "parse_concurrency")
+        )]
+        concurrency: usize,
 
-#[derive(Debug, Deserialize, Serialize)]
-struct KeyringEntry {
-    access_token: String,
-    expires_at: SystemTime,
-}
+        /// Accept log drains over plain http://
+        #[structopt(long = "allow-insecure-log-drain")]
+        allow_insecure_log_drain: bool,
 
-fn view(client: Client, host: String) -> Result<(), Error> {
-    let mut response = client.get(format!("/hosts/{}", host))?.send()?;
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-    handle_error("", &mut response)?;
+    /// Create every host listed in a YAML or JSON manifest
+    #[structopt(name = "host:create-from-file")]
+    CreateFromFile {
+        #[structopt(name = "FILE")]
+        file: String,
 
-    let response: JsonValue = response.json()?;
+        /// What to do with hosts that already exist: skip, update or error
+        #[structopt(long = "on-conflict", default_value = "error")]
+        on_conflict: OnConflict,
 
-    println!("{:#}", response);
+        /// Number of hosts to create in parallel (at most 16)
+        #[structopt(
+            long = "concurrency",
+            default_value = "1",
+            parse(try_from_str = "parse_concurrency")
+        )]
+        concurrency: usize,
 
-    Ok(())
-}
+        /// Accept log drains over plain http://
+        #[structopt(long = "allow-insecure-log-drain")]
+        allow_insecure_log_drain: bool,
 
-fn upload(client: Client, module_path: String) -> Result<(), Error> {
-    let module_id = do_upload(&client, &module_path)?;
-    println!("{}", module_id);
-    Ok(())
-}
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-fn create(
-    client: Client,
-    host: String,
-    customer_id: String,
-    configuration: ConfigureOpts,
-) -> Result<(), Error> {
-    #[derive(Debug, Serialize)]
-    struct CreateBody {
-        host: String,
-        customer_id: String,
+    /// Print a value from ~/.wasp/config.toml
+    #[structopt(name = "config:get")]
+    ConfigGet {
+        #[structopt(name = "KEY")]
+        key: String,
+    },
 
-        #[serde(skip_serializing_if = "Option::is_none")]
-        module: Option<String>,
+    /// Set a value in ~/.wasp/config.toml
+    #[structopt(name = "config:set")]
+    ConfigSet {
+        #[structopt(name = "KEY")]
+        key: String,
 
-        #[serde(skip_serializing_if = "Option::is_none")]
-        function: Option<String>,
+        #[structopt(name = "VALUE")]
+        value: String,
 
-        #[serde(skip_serializing_if = "HashMap::is_empty")]
-        env: HashMap<String, JsonValue>,
-    }
+        /// Allow keys this version of wasp does not know about
+        #[structopt(long = "force")]
+        force: bool,
+    },
 
-    let mut response = client
-        .post("/hosts")?
-        .json(&CreateBody {
-            host,
-            customer_id,
-            module: maybe_upload(&client, configuration.module)?,
-            function: configuration.function,
-            env: configuration.env.into_iter().collect(),
-        })
-        .send()?;
+    /// Login to wasp
+    #[structopt(name = "login")]
+    Login {
+        #[structopt(name = "USERNAME", required_unless = "token")]
+        username: Option<String>,
 
-    handle_error("", &mut response)?;
+        /// Store this access token instead of prompting for a password
+        #[structopt(long = "token")]
+        token: Option<String>,
 
-    eprintln!("Ok");
+        /// Lifetime of the token in seconds; asked of the server when logging in
+        /// with a password, and 3600 by default with --token
+        #[structopt(long = "expires-in")]
+        expires_in: Option<u64>,
 
-    Ok(())
-}
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
 
-fn configure(client: Client, host: String, configuration: ConfigureOpts) -> Result<(), Error> {
-    #[derive(Debug, Default, Serialize)]
-    struct ConfigureBody {
-        #[serde(skip_serializing_if = "Option::is_none")]
-        module: Option<String>,
+    /// Write the local KVS to a JSON file
+    #[structopt(name = "kvs:export")]
+    KvsExport {
+        #[structopt(short = "k", long = "kvs-directory", default_value = ".db")]
+        directory: String,
 
-        #[serde(skip_serializing_if = "Option::is_none")]
-        function: Option<String>,
+        /// File to write; stdout by default
+        #[structopt(name = "FILE")]
+        output: Option<String>,
 
-        #[serde(skip_serializing_if = "HashMap::is_empty")]
-        env: HashMap<String, JsonValue>,
-    }
+        /// Write one {"key":...,"value":...} object per line
+        #[structopt(long = "ndjson")]
+        ndjson: bool,
+    },
 
-    let mut response = client
-        .post(format!("/hosts/{}", host))?
-        .json(&ConfigureBody {
-            module: maybe_upload(&client, configuration.module)?,
-            function: configuration.function,
-            env: configuration.env.into_iter().collect(),
-        })
-        .send()?;
+    /// Load a kvs:export file into the local KVS
+    #[structopt(name = "kvs:import")]
+    KvsImport {
+        #[structopt(short = "k", long = "kvs-directory", default_value = ".db")]
+        directory: String,
 
-    handle_error("", &mut response)?;
+        /// JSON file, or NDJSON if it ends in .ndjson
+        #[structopt(name = "FILE")]
+        file: String,
 
-    eprintln!("Ok");
+        /// Keep keys that aren't in FILE instead of clearing the KVS first
+        #[structopt(long = "merge")]
+        merge: bool,
+    },
 
-    Ok(())
+    /// Print the client and server versions
+    #[structopt(name = "version")]
+    Version {
+        #[structopt(flatten)]
+        output: OutputOpts,
+
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
+
+    /// Remove local wasp credentials
+    #[structopt(name = "logout")]
+    Logout {
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
+
+    /// Check the keyring, login, API connection and ~/.wasp for problems
+    #[structopt(name = "doctor")]
+    Doctor {
+        #[structopt(flatten)]
+        source: SourceOpts,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct RunOpts {
+    #[structopt(name = "MODULE")]
+    module: String,
+
+    /// Entry function (default `run`), or NAME=ROUTE to mount another function (repeatable)
+    #[structopt(
+        short = "f",
+        long = "function",
+        parse(try_from_str = "parse_function_route")
+    )]
+    functions: Vec<(String, Option<String>)>,
+
+    /// PREFIX=MODULE_PATH[:FUNCTION] to serve requests under PREFIX with
+    /// another module (repeatable); MODULE serves the remaining requests
+    #[structopt(long = "mount", parse(try_from_str = "parse_mount"))]
+    mounts: Vec<Mount>,
+
+    /// Port to listen on [default: $PORT or 5000]
+    #[structopt(short = "p", long = "port")]
+    port: Option<usize>,
+
+    /// Address to listen on; use 127.0.0.1 to keep the server off the network
+    // TODO default to 127.0.0.1 in the next breaking release
+    #[structopt(long = "bind", default_value = "0.0.0.0")]
+    bind: IpAddr,
+
+    #[structopt(short = "e", long = "env-file")]
+    env_file: Option<String>,
+
+    /// Directory of static files served alongside the module
+    #[structopt(short = "c", long = "cdn-directory")]
+    cdn_directory: Option<String>,
+
+    /// Same as --cdn-directory
+    #[structopt(long = "static-dir")]
+    static_dir: Option<String>,
+
+    /// Directory of static files that are protected from public access
+    #[structopt(short = "P", long = "protected-cdn-directory")]
+    protected_cdn_directory: Option<String>,
+
+    /// Same as --protected-cdn-directory
+    #[structopt(long = "protected-static-dir")]
+    protected_static_dir: Option<String>,
+
+    /// Cache-Control header sent with CDN files, e.g. "public, max-age=3600"
+    #[structopt(long = "cdn-cache-control")]
+    cdn_cache_control: Option<String>,
+
+    /// File in the CDN directory served for unknown paths, e.g. index.html for SPAs
+    #[structopt(long = "cdn-fallback")]
+    cdn_fallback: Option<String>,
+
+    #[structopt(short = "k", long = "kvs-directory", default_value = ".db")]
+    kvs_directory: String,
+
+    /// JSON object of keys to write into the KVS before starting
+    #[structopt(long = "seed-kvs")]
+    seed_kvs: Option<String>,
+
+    /// Maximum requests per second before responding with 429; 0 disables the limit
+    #[structopt(long = "rate-limit", default_value = "0")]
+    rate_limit: u32,
+
+    /// Number of requests allowed to burst above the rate limit
+    #[structopt(long = "rate-limit-burst")]
+    rate_limit_burst: Option<u32>,
+
+    /// Maximum WASM linear memory in MB (16 pages of 64KB each); unlimited by default
+    #[structopt(long = "wasm-memory-limit")]
+    wasm_memory_limit: Option<u32>,
+
+    /// Origin allowed to make cross-origin requests, or * for any (repeatable)
+    #[structopt(long = "cors-origin", name = "ORIGIN")]
+    cors_origins: Vec<String>,
+
+    /// Methods allowed in cross-origin requests, comma separated
+    #[structopt(
+        long = "cors-methods",
+        default_value = "GET,POST,PUT,PATCH,DELETE,OPTIONS",
+        requires = "ORIGIN"
+    )]
+    cors_methods: String,
+
+    /// Request headers allowed in cross-origin requests, comma separated
+    #[structopt(long = "cors-headers", requires = "ORIGIN")]
+    cors_headers: Option<String>,
+
+    /// Largest request body in bytes, passed on as WASP_MAX_BODY_SIZE; bigger
+    /// requests get a 413
+    #[structopt(long = "max-body-size", default_value = "10485760")]
+    max_body_size: u64,
+
+    /// Milliseconds a request may run before responding with 504; 0 disables the timeout
+    #[structopt(long = "timeout-ms", default_value = "30000")]
+    timeout_ms: u64,
+
+    /// Milliseconds allowed for instantiating the module; unlimited by default
+    #[structopt(long = "startup-timeout-ms")]
+    startup_timeout_ms: Option<u64>,
+
+    /// CPU milliseconds a request may use per second of wall time; unlimited by default
+    #[structopt(long = "cpu-limit")]
+    cpu_limit: Option<u32>,
+
+    /// Send a copy of every request to this URL; its responses are discarded
+    #[structopt(long = "mirror-requests", parse(try_from_str = "parse_http_url"))]
+    mirror_requests: Option<String>,
+
+    /// Fraction of requests to mirror, from 0.0 to 1.0
+    #[structopt(
+        long = "mirror-ratio",
+        default_value = "1.0",
+        parse(try_from_str = "parse_ratio")
+    )]
+    mirror_ratio: f64,
+
+    /// OTLP collector to export a trace span per request to, e.g.
+    /// http://localhost:4317. Needs a wasp-app-route built with OpenTelemetry
+    /// support.
+    #[structopt(long = "otlp-endpoint", parse(try_from_str = "parse_http_url"))]
+    otlp_endpoint: Option<String>,
+
+    /// Lua (.lua) or WASM (.wasm) script run on every response before it's
+    /// sent. Lua scripts define `transform(status, headers, body)` and return
+    /// the new status, headers and body; headers is a table of name to value
+    /// and body a string. WASM scripts export a `transform` function that
+    /// takes and returns the same values as one JSON object
+    /// {"status":...,"headers":{...},"body":"<base64>"}.
+    #[structopt(
+        long = "response-transform",
+        parse(try_from_str = "parse_response_transform")
+    )]
+    response_transform: Option<PathBuf>,
+
+    /// Append every request body to FILE, or to stderr with -
+    #[structopt(long = "tee-request-body", name = "REQUEST_FILE")]
+    tee_request_body: Option<String>,
+
+    /// Append every response body to FILE, or to stderr with -
+    #[structopt(long = "tee-response-body", name = "RESPONSE_FILE")]
+    tee_response_body: Option<String>,
+
+    /// Request header carrying the request id; requests without it get a new
+    /// UUID. The id is tagged on every log line of the request and the module
+    /// reads it from the WASP_REQUEST_ID env var.
+    #[structopt(long = "request-id-header", default_value = "X-Request-Id")]
+    request_id_header: String,
+
+    /// Request header whose KEY=VALUE,KEY2=VALUE2 value overrides env vars for
+    /// that request only. Development only; hosted wasp ignores it.
+    #[structopt(long = "env-override-header", default_value = "X-Wasp-Env")]
+    env_override_header: String,
+
+    /// Host header the module sees instead of the request's, e.g. for
+    /// multi-tenant routing; several hosts (comma separated or repeated) are
+    /// used in turn
+    #[structopt(long = "forward-host-header", name = "FORWARD_HOST")]
+    forward_hosts: Vec<String>,
+
+    /// Log line format: text, or json for one NDJSON object per line
+    #[structopt(
+        long = "log-format",
+        default_value = "text",
+        raw(possible_values = r#"&["text", "json"]"#)
+    )]
+    log_format: String,
+
+    /// Milliseconds to wait before handing each request to the module, e.g. to
+    /// see loading states
+    #[structopt(long = "latency-inject-ms", name = "LATENCY_MS")]
+    latency_inject_ms: Option<u64>,
+
+    /// Up to this many milliseconds added to or taken off each delay at random
+    #[structopt(
+        long = "latency-jitter-ms",
+        default_value = "0",
+        requires = "LATENCY_MS"
+    )]
+    latency_jitter_ms: u64,
+
+    /// Only delay requests whose path matches REGEX
+    #[structopt(long = "latency-path-pattern", name = "REGEX", requires = "LATENCY_MS")]
+    latency_path_pattern: Option<Regex>,
+
+    /// Shut down after this many requests, e.g. at the end of a test run; 0 never does
+    #[structopt(long = "max-requests", default_value = "0")]
+    max_requests: u64,
+
+    /// Milliseconds the runtime may let in-flight requests finish after Ctrl-C
+    /// or SIGTERM, passed on as WASP_GRACEFUL_SHUTDOWN_MS
+    #[structopt(
+        long = "graceful-shutdown-timeout",
+        default_value = "5000",
+        raw(visible_alias = r#""shutdown-timeout""#)
+    )]
+    graceful_shutdown_timeout: u64,
+}
+
+#[derive(Debug, StructOpt)]
+struct ConfigureOpts {
+    /// Module id, or path of a module file to upload
+    #[structopt(short = "m", long = "module")]
+    module: Option<String>,
+
+    /// Id of an already uploaded module
+    #[structopt(
+        long = "module-id",
+        raw(conflicts_with_all = r#"&["module", "module_file"]"#)
+    )]
+    module_id: Option<String>,
+
+    /// Path of a module file to upload
+    #[structopt(long = "module-file", conflicts_with = "module")]
+    module_file: Option<String>,
+
+    /// Use the module TAG currently points at
+    #[structopt(
+        long = "module-from-tag",
+        name = "TAG",
+        raw(conflicts_with_all = r#"&["module", "module_id", "module_file"]"#)
+    )]
+    module_tag: Option<String>,
+
+    /// Function the host calls on each request; unrelated to `run --function`
+    #[structopt(short = "f", long = "function")]
+    function: Option<String>,
+
+    /// Wait for the uploaded module to compile and check that --function is one of its exports
+    #[structopt(long = "verify")]
+    verify: bool,
+
+    /// JSON file with the module's args: an object of named args or an array
+    /// of positional ones
+    #[structopt(long = "args-file")]
+    args_file: Option<String>,
+
+    /// The module's args as JSON, e.g. '{"greeting":"hi"}' for named args or
+    /// '["hi", 2]' for positional ones; values can be any JSON type
+    #[structopt(
+        long = "function-args",
+        name = "ARGS_JSON",
+        conflicts_with = "args_file",
+        parse(try_from_str = "parse_args")
+    )]
+    function_args: Option<JsonValue>,
+
+    /// NAME=VALUE arg, or INDEX=VALUE for positional args (repeatable);
+    /// VALUE is parsed as JSON when it can be, overriding --args-file
+    #[structopt(long = "arg", parse(try_from_str = "parse_arg"))]
+    args: Vec<(String, JsonValue)>,
+
+    #[structopt(short = "e", long = "env", parse(try_from_str = "parse_env"))]
+    env: Vec<(String, JsonValue)>,
+
+    /// JSON object file whose keys are added to the env with their types kept;
+    /// --env overrides them
+    #[structopt(long = "env-json", name = "ENV_FILE")]
+    env_json: Option<String>,
+
+    /// Env var whose value is masked in diagnostic output (repeatable)
+    #[structopt(short = "E", long = "secret-env", parse(try_from_str = "parse_env"))]
+    secret_env: Vec<(String, JsonValue)>,
+
+    /// KEY=vault://MOUNT/data/PATH[#FIELD] to set KEY from a Vault KV v2
+    /// secret, read with VAULT_ADDR and VAULT_TOKEN just before sending (repeatable)
+    #[structopt(
+        long = "env-from-secret",
+        parse(try_from_str = "parse_env_from_secret")
+    )]
+    env_from_secret: Vec<(String, String)>,
+
+    /// HTTPS endpoint that receives the host's log lines (repeatable)
+    #[structopt(long = "log-drain")]
+    log_drains: Vec<String>,
+
+    /// Send this percentage of requests to the new module and the rest to the
+    /// current one; 100 completes the rollout
+    #[structopt(long = "rollout-percent", parse(try_from_str = "parse_percent"))]
+    rollout_percent: Option<u8>,
+
+    /// Directory of static files the host serves alongside the module
+    #[structopt(long = "cdn-directory")]
+    cdn_directory: Option<String>,
+
+    /// Same as --cdn-directory
+    #[structopt(long = "static-dir")]
+    static_dir: Option<String>,
+
+    /// Also run the entry function on this 5-field cron schedule, e.g. "*/15 * * * *"
+    #[structopt(long = "schedule", name = "CRON")]
+    schedule: Option<String>,
+
+    /// IANA timezone the schedule is evaluated in, e.g. Europe/Berlin [default: UTC]
+    #[structopt(long = "schedule-tz", name = "TZ", requires = "CRON")]
+    schedule_tz: Option<String>,
+
+    /// Accept log drains over plain http://
+    #[structopt(long = "allow-insecure-log-drain")]
+    allow_insecure_log_drain: bool,
+
+    /// JSON or YAML file with the full host spec; flags override its fields
+    #[structopt(long = "spec")]
+    spec: Option<String>,
+
+    /// Print the request body instead of sending it
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// Show secret env values in --dry-run and --verbose output
+    #[structopt(long = "show-secrets")]
+    show_secrets: bool,
+
+    // formats the --dry-run body
+    #[structopt(flatten)]
+    output: OutputOpts,
+
+    /// Replace the host's env with the one from --spec and --env instead of
+    /// merging them, removing every other env var
+    #[structopt(long = "clear-env")]
+    clear_env: bool,
+
+    /// Don't ask for --clear-env to be confirmed
+    #[structopt(long = "yes")]
+    yes: bool,
+
+    /// Idempotency-Key sent with the request [default: a hash of the host and body]
+    #[structopt(long = "idempotency-key")]
+    idempotency_key: Option<String>,
+}
+
+impl ConfigureOpts {
+    /// Loads `--spec`, if any, and applies the individual flags on top of it
+    fn into_spec(self) -> Result<HostSpec, Error> {
+        let mut spec = match &self.spec {
+            Some(path) => {
+                let mut spec = HostSpec::load(path)?;
+                spec.module_base = Path::new(path)
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .to_owned();
+                spec
+            }
+            None => HostSpec::default(),
+        };
+
+        // any of the module flags replaces whatever module the spec names
+        if self.module.is_some()
+            || self.module_id.is_some()
+            || self.module_file.is_some()
+            || self.module_tag.is_some()
+        {
+            spec.module = self.module;
+            spec.module_id = self.module_id;
+            spec.module_file = self.module_file;
+            spec.module_tag = self.module_tag;
+            spec.module_base = PathBuf::new();
+        }
+        if self.clear_env {
+            // scripts can't answer a prompt, so only interactive use needs --yes
+            if std::io::stdin().is_terminal() && !self.yes {
+                return Err(format_err!(
+                    "--clear-env removes every env var of the host not given with \
+                     --spec or --env; pass --yes to confirm"
+                ));
+            }
+        }
+
+        spec.function = self.function.or(spec.function);
+        spec.rollout_percent = self.rollout_percent.or(spec.rollout_percent);
+        if let Some(dir) = merge_alias(
+            "--cdn-directory",
+            self.cdn_directory,
+            "--static-dir",
+            self.static_dir,
+        )? {
+            spec.cdn_directory = Some(dir);
+        }
+        if let Some(schedule) = self.schedule {
+            spec.schedule = Some(schedule);
+            spec.schedule_tz = self.schedule_tz;
+        }
+        if let Some(schedule) = spec.schedule.as_deref() {
+            validate_schedule(schedule)?;
+            match spec.schedule_tz.as_deref() {
+                Some(tz) => validate_timezone(tz)?,
+                None => spec.schedule_tz = Some("UTC".to_owned()),
+            }
+        }
+        if let Some(function) = spec.function.as_deref() {
+            if !function.is_empty() && !is_identifier(function) {
+                return Err(format_err!(
+                    "Invalid function {:?}: expected letters, digits and underscores",
+                    function
+                ));
+            }
+        }
+        if let Some(file) = &self.args_file {
+            let contents = std::fs::read(file)
+                .map_err(|err| format_err!("Could not open {}: {}", file, err))?;
+            spec.args = Some(
+                serde_json::from_slice(&contents)
+                    .map_err(|err| format_err!("Invalid JSON in {}: {}", file, err))?,
+            );
+        }
+        if let Some(args) = self.function_args {
+            spec.args = Some(args);
+        }
+        if let Some(args) = &spec.args {
+            if !args.is_object() && !args.is_array() {
+                return Err(format_err!(
+                    "args must be a JSON object or array, not {}",
+                    args
+                ));
+            }
+        }
+        for (name, value) in self.args {
+            set_arg(&mut spec.args, &name, value)?;
+        }
+
+        if let Some(file) = &self.env_json {
+            spec.env.extend(load_env_json(file)?);
+        }
+        spec.env.extend(self.env);
+
+        for (name, value) in self.secret_env {
+            spec.secret_keys.insert(name.clone());
+            spec.env.insert(name, value);
+        }
+        for (name, reference) in self.env_from_secret {
+            spec.env.insert(name, JsonValue::String(reference));
+        }
+
+        if !self.log_drains.is_empty() {
+            spec.log_drains = self.log_drains;
+        }
+
+        spec.check_log_drains(self.allow_insecure_log_drain)?;
+
+        spec.mark_secrets(self.show_secrets);
+
+        Ok(spec)
+    }
+}
+
+/// Sets a named arg, or a positional one when the args are an array
+fn set_arg(args: &mut Option<JsonValue>, name: &str, value: JsonValue) -> Result<(), Error> {
+    match args.get_or_insert_with(|| JsonValue::Object(Default::default())) {
+        JsonValue::Object(args) => {
+            args.insert(name.to_owned(), value);
+        }
+        JsonValue::Array(args) => {
+            let slot = name
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| args.get_mut(index))
+                .ok_or_else(|| {
+                    format_err!(
+                        "--arg {} must be an index below {} for positional args",
+                        name,
+                        args.len()
+                    )
+                })?;
+            *slot = value;
+        }
+        _ => unreachable!("args are checked to be an object or array"),
+    }
+    Ok(())
+}
+
+fn load_env_json(file: &str) -> Result<serde_json::Map<String, JsonValue>, Error> {
+    let contents =
+        std::fs::read(file).map_err(|err| format_err!("Could not open {}: {}", file, err))?;
+    match serde_json::from_slice(&contents)
+        .map_err(|err| format_err!("Invalid JSON in {}: {}", file, err))?
+    {
+        JsonValue::Object(env) => Ok(env),
+        _ => Err(format_err!("{} must contain a JSON object", file)),
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map_or(false, |first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Declarative host definition as read from a `--spec` file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostSpec {
+    host: Option<String>,
+    customer_id: Option<String>,
+    module: Option<String>,
+    module_id: Option<String>,
+    module_file: Option<String>,
+    module_tag: Option<String>,
+    function: Option<String>,
+
+    #[serde(default)]
+    env: HashMap<String, JsonValue>,
+
+    args: Option<JsonValue>,
+
+    #[serde(default)]
+    log_drains: Vec<String>,
+
+    rollout_percent: Option<u8>,
+
+    cdn_directory: Option<String>,
+
+    schedule: Option<String>,
+    schedule_tz: Option<String>,
+
+    /// Env vars to mask in diagnostic output, on top of the ones that look secret
+    #[serde(default)]
+    secret_keys: BTreeSet<String>,
+
+    /// Directory module file paths are resolved against; the spec file's
+    /// own, or the working directory for modules given as flags
+    #[serde(skip)]
+    module_base: PathBuf,
+}
+
+impl HostSpec {
+    /// Resolves `secret_keys` to the env vars that should be masked
+    ///
+    /// Besides the explicitly listed keys, anything `looks_secret` accepts is
+    /// treated as a secret.
+    fn mark_secrets(&mut self, show_secrets: bool) {
+        if show_secrets {
+            self.secret_keys.clear();
+            return;
+        }
+
+        let explicit = std::mem::take(&mut self.secret_keys);
+        self.secret_keys = self
+            .env
+            .keys()
+            .filter(|name| explicit.contains(name.as_str()) || looks_secret(name))
+            .cloned()
+            .collect();
+    }
+
+    fn load(path: &str) -> Result<Self, Error> {
+        load_spec_file(path)
+    }
+
+    /// Checks the log drains, accepting plain http:// ones only with
+    /// `allow_insecure`
+    fn check_log_drains(&mut self, allow_insecure: bool) -> Result<(), Error> {
+        self.log_drains = self
+            .log_drains
+            .iter()
+            .map(|drain| parse_log_drain(drain, allow_insecure))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Builds the request body, uploading the module unless this is a dry run
+    ///
+    /// With `verify` the module must have compiled on the server and export
+    /// the function, if one is set.
+    async fn into_body(
+        mut self,
+        client: &Client,
+        dry_run: bool,
+        verify: bool,
+    ) -> Result<ConfigureBody, Error> {
+        let base = std::mem::take(&mut self.module_base);
+        let module = self.take_module(&base)?;
+        let module = if dry_run {
+            module.map(|module| module.to_string())
+        } else {
+            maybe_upload(client, module).await?
+        };
+
+        // dry runs print the vault:// references instead of the secrets
+        if !dry_run {
+            self.resolve_secrets().await?;
+        }
+
+        if let (true, false, Some(module_id)) = (verify, dry_run, &module) {
+            let functions = verify_module(client, module_id).await?;
+            if let Some(function) = self.function.as_deref().filter(|name| !name.is_empty()) {
+                if !functions.iter().any(|name| name == function) {
+                    return Err(format_err!(
+                        "Function {:?} not found in module {}; available: {}",
+                        function,
+                        module_id,
+                        functions.join(", ")
+                    ));
+                }
+            }
+        }
+
+        Ok(self.body_with_module(module))
+    }
+
+    /// Reads `vault://` env values from Vault; they stay masked in diagnostic
+    /// output even with --show-secrets
+    async fn resolve_secrets(&mut self) -> Result<(), Error> {
+        let resolved = wasp::vault::resolve_env(&mut self.env).await?;
+        self.secret_keys.extend(resolved);
+        Ok(())
+    }
+
+    fn body_with_module(self, module: Option<String>) -> ConfigureBody {
+        ConfigureBody {
+            module,
+            function: self.function,
+            env: Some(self.env).filter(|env| !env.is_empty()),
+            args: self.args,
+            log_drains: self.log_drains,
+            rollout_percent: self.rollout_percent,
+            cdn_directory: self.cdn_directory,
+            schedule: self.schedule,
+            schedule_tz: self.schedule_tz,
+            secret_keys: self.secret_keys,
+            idempotency_key: None,
+        }
+    }
+
+    /// Takes the module out of the spec, resolving file paths against `base`
+    ///
+    /// `module_id` and `module_file` say explicitly what they are, though
+    /// either `module_file` or `module` may be an http(s) URL. The
+    /// ambiguous `module` is a file if it exists and an id otherwise, unless
+    /// it looks like a path (contains `/` or ends in `.wasm`), in which case
+    /// a missing file is an error.
+    fn take_module(&mut self, base: &Path) -> Result<Option<ModuleRef>, Error> {
+        if let Some(tag) = self.module_tag.take() {
+            if self.module.is_some() || self.module_id.is_some() || self.module_file.is_some() {
+                return Err(format_err!(
+                    "Only one of module, module_id, module_file and module_tag can be given"
+                ));
+            }
+            return Ok(Some(ModuleRef::Tag(tag)));
+        }
+
+        match (
+            self.module.take(),
+            self.module_id.take(),
+            self.module_file.take(),
+        ) {
+            (None, None, None) => Ok(None),
+            (None, Some(id), None) => Ok(Some(ModuleRef::Id(id))),
+            (None, None, Some(file)) if is_url(&file) => Ok(Some(ModuleRef::Url(file))),
+            (None, None, Some(file)) => Ok(Some(ModuleRef::File(base.join(file)))),
+            (Some(module), None, None) if is_url(&module) => Ok(Some(ModuleRef::Url(module))),
+            (Some(module), None, None) => {
+                let path = base.join(&module);
+                if path.exists() {
+                    Ok(Some(ModuleRef::File(path)))
+                } else if module.contains('/') || module.ends_with(".wasm") {
+                    Err(format_err!("Module file {} does not exist", path.display()))
+                } else {
+                    Ok(Some(ModuleRef::Id(module)))
+                }
+            }
+            _ => Err(format_err!(
+                "Only one of module, module_id, module_file and module_tag can be given"
+            )),
+        }
+    }
+}
+
+/// Where the module for a host comes from
+#[derive(Clone, Debug, PartialEq)]
+enum ModuleRef {
+    /// A module that was already uploaded
+    Id(String),
+    /// A local module file that still has to be uploaded
+    File(PathBuf),
+    /// An http(s) URL to download the module from before uploading it
+    Url(String),
+    /// A tag to resolve to the module it points at
+    Tag(String),
+}
+
+impl std::fmt::Display for ModuleRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ModuleRef::Id(id) => write!(f, "{}", id),
+            ModuleRef::File(path) => write!(f, "{}", path.display()),
+            ModuleRef::Url(url) => write!(f, "{}", url),
+            ModuleRef::Tag(tag) => write!(f, "tag:{}", tag),
+        }
+    }
+}
+
+/// Reads a YAML file when the extension says so and JSON otherwise
+fn load_spec_file<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, Error> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| format_err!("Could not open spec {}: {}", path, err))?;
+
+    let spec = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_reader(file)
+            .map_err(|err| format_err!("Invalid spec {}: {}", path, err))?,
+        _ => serde_json::from_reader(file)
+            .map_err(|err| format_err!("Invalid spec {}: {}", path, err))?,
+    };
+
+    Ok(spec)
+}
+
+/// What to do when a host in a manifest already exists
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OnConflict {
+    Skip,
+    Update,
+    Error,
+}
+
+impl std::str::FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "skip" => Ok(OnConflict::Skip),
+            "update" => Ok(OnConflict::Update),
+            "error" => Ok(OnConflict::Error),
+            _ => Err(format!(
+                "unknown conflict strategy {:?}, expected skip, update or error",
+                input
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct EnvFilterOpts {
+    /// Only print env vars whose name starts with PREFIX
+    #[structopt(long = "env-prefix", name = "PREFIX", conflicts_with = "KEY")]
+    prefix: Option<String>,
+
+    /// Only print env vars whose name matches REGEX
+    #[structopt(long = "env-grep", name = "REGEX", conflicts_with = "KEY")]
+    grep: Option<Regex>,
+}
+
+impl EnvFilterOpts {
+    fn matches(&self, name: &str) -> bool {
+        self.prefix
+            .as_ref()
+            .map_or(true, |prefix| name.starts_with(prefix.as_str()))
+            && self.grep.as_ref().map_or(true, |grep| grep.is_match(name))
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct OutputOpts {
+    /// Output format: text, json, toml, yaml, table or env
+    #[structopt(
+        short = "o",
+        long = "output",
+        default_value = "text",
+        raw(visible_aliases = r#"&["format", "output-format"]"#)
+    )]
+    output: OutputFormat,
+
+    /// Print JSON output on a single line instead of indented
+    #[structopt(long = "compact")]
+    compact: bool,
+}
+
+impl OutputOpts {
+    fn format(&self) -> OutputFormat {
+        match self.output {
+            OutputFormat::Json if self.compact => OutputFormat::CompactJson,
+            output => output,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+    /// `json` with `--compact`
+    CompactJson,
+    Toml,
+    Yaml,
+    Table,
+    /// `export KEY='value'` lines for `eval`
+    Env,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "toml" => Ok(OutputFormat::Toml),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "table" => Ok(OutputFormat::Table),
+            "env" => Ok(OutputFormat::Env),
+            _ => Err(format!("Unknown output format {:?}", input)),
+        }
+    }
+}
+
+const DEFAULT_API: &str = "https://api.wasp.ws";
+
+#[derive(Debug, StructOpt)]
+struct SourceOpts {
+    /// API to talk to [default: `api` from the config or https://api.wasp.ws]
+    #[structopt(short = "a", long = "api")]
+    api: Option<String>,
+
+    /// Account to use [default: `account` from the config or "default"]; also
+    /// API|ACCOUNT, or without --api the name of a [profile.NAME] to use its
+    /// api and account
+    #[structopt(short = "A", long = "account")]
+    account: Option<String>,
+
+    /// Use the api and account from [profile.NAME] in ~/.wasp/config.toml
+    #[structopt(long = "profile", env = "WASP_PROFILE")]
+    profile: Option<String>,
+
+    /// Number of times to retry transient failures; 0 disables retries
+    #[structopt(long = "retries", default_value = "3")]
+    retries: u32,
+
+    /// Base delay in milliseconds before retrying, doubled on each attempt up
+    /// to 5 minutes
+    #[structopt(long = "retry-delay", default_value = "500")]
+    retry_delay: u64,
+
+    /// Speak HTTP/2 without negotiating it first, for gateways that support it
+    #[structopt(long = "http2")]
+    http2: bool,
+
+    /// Seconds to wait for a connection to the API; unlimited by default
+    #[structopt(long = "connect-timeout")]
+    connect_timeout: Option<u64>,
+
+    /// Seconds a whole request, including the response body, may take;
+    /// unlimited by default
+    #[structopt(long = "timeout")]
+    timeout: Option<u64>,
+
+    /// Redirects to follow per request, 0 to fail on any redirect [default: 10]
+    #[structopt(long = "max-redirects")]
+    max_redirects: Option<usize>,
+
+    /// Sign API requests with HMAC-SHA256 of method, URL and timestamp, for
+    /// deployments that require it
+    #[structopt(
+        long = "request-signing-key",
+        env = "WASP_SIGNING_KEY",
+        raw(hide_env_values = "true")
+    )]
+    signing_key: Option<String>,
+
+    /// Append each API request's method, URL, headers and body size and
+    /// SHA-256 to FILE as a JSON line; credentials are never written
+    #[structopt(long = "dump-request", name = "DUMP_FILE", parse(from_os_str))]
+    dump_request: Option<PathBuf>,
+
+    /// USER:PASSWORD for a basic-auth gateway in front of the API, sent as
+    /// Proxy-Authorization so it doesn't replace the wasp token
+    #[structopt(
+        long = "proxy-auth",
+        env = "WASP_PROXY_AUTH",
+        raw(visible_alias = r#""gateway-auth""#, hide_env_values = "true"),
+        parse(try_from_str = "parse_proxy_auth")
+    )]
+    proxy_auth: Option<String>,
+}
+
+/// Turns `USER:PASSWORD` into a basic credential
+fn parse_proxy_auth(input: &str) -> Result<String, String> {
+    if !input.contains(':') {
+        return Err("expected USER:PASSWORD".to_owned());
+    }
+    Ok(format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(input)
+    ))
+}
+
+fn parse_env_from_secret(input: &str) -> Result<(String, String), String> {
+    let (name, reference) = input
+        .split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| "expected KEY=vault://PATH".to_owned())?;
+    wasp::vault::SecretRef::parse(reference)?;
+    Ok((name.to_owned(), reference.to_owned()))
+}
+
+/// Parses `--function-args`, which the server only accepts as an object or array
+fn parse_args(input: &str) -> Result<JsonValue, String> {
+    match serde_json::from_str(input) {
+        Ok(args @ JsonValue::Object(_)) | Ok(args @ JsonValue::Array(_)) => Ok(args),
+        Ok(_) => Err("args must be a JSON object or array".to_owned()),
+        Err(err) => Err(format!("invalid JSON: {}", err)),
+    }
+}
+
+fn parse_arg(input: &str) -> Result<(String, JsonValue), String> {
+    let mut parts = input.splitn(2, '=');
+    let name = parts.next().filter(|name| !name.is_empty());
+    match (name, parts.next()) {
+        (Some(name), Some(value)) => Ok((
+            name.to_owned(),
+            serde_json::from_str(value).unwrap_or_else(|_| JsonValue::String(value.to_owned())),
+        )),
+        _ => Err("expected NAME=VALUE".to_owned()),
+    }
+}
+
+fn parse_function_route(input: &str) -> Result<(String, Option<String>), String> {
+    let mut parts = input.splitn(2, '=');
+    let name = parts.next().unwrap_or_default().to_owned();
+    let route = parts.next().map(str::to_owned);
+
+    if name.is_empty() {
+        return Err("Missing function name".to_owned());
+    }
+
+    match route {
+        Some(ref route) if !route.starts_with('/') => {
+            Err(format!("Route {:?} must start with /", route))
+        }
+        route => Ok((name, route)),
+    }
+}
+
+/// A module served under a path prefix by `run --mount`
+#[derive(Debug)]
+struct Mount {
+    prefix: String,
+    module: String,
+    function: String,
+}
+
+fn parse_mount(input: &str) -> Result<Mount, String> {
+    let (prefix, target) = input
+        .split_once('=')
+        .ok_or_else(|| "expected PREFIX=MODULE_PATH[:FUNCTION]".to_owned())?;
+    if !prefix.starts_with('/') {
+        return Err(format!("Prefix {:?} must start with /", prefix));
+    }
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return Err("Prefix / would catch every request; MODULE serves those".to_owned());
+    }
+
+    // a trailing `:name` is the function unless it's part of the path
+    let (module, function) = match target.rsplit_once(':') {
+        Some((module, function)) if is_identifier(function) => (module, function),
+        _ => (target, "run"),
+    };
+    if !Path::new(module).is_file() {
+        return Err(format!("Module file {} does not exist", module));
+    }
+    if module.contains(',') {
+        return Err(format!("Module path {:?} can't contain a comma", module));
+    }
+
+    Ok(Mount {
+        prefix: prefix.to_owned(),
+        module: module.to_owned(),
+        function: function.to_owned(),
+    })
+}
+
+/// Rejects two mounts on the same prefix; nested prefixes are fine as the
+/// longest match wins
+fn check_mounts(mounts: &[Mount]) -> Result<(), Error> {
+    let mut prefixes = HashSet::new();
+    for mount in mounts {
+        if !prefixes.insert(mount.prefix.as_str()) {
+            return Err(format_err!(
+                "--mount {} is given more than once",
+                mount.prefix
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Combines a flag with its alias, which may both be given as long as they agree
+fn merge_alias(
+    flag: &str,
+    value: Option<String>,
+    alias: &str,
+    alias_value: Option<String>,
+) -> Result<Option<String>, Error> {
+    match (value, alias_value) {
+        (Some(value), Some(alias_value)) if value != alias_value => Err(format_err!(
+            "{} {} and {} {} disagree",
+            flag,
+            value,
+            alias,
+            alias_value
+        )),
+        (value, alias_value) => Ok(value.or(alias_value)),
+    }
+}
+
+/// Accepts http(s) URLs; clap's error already names the flag
+fn parse_http_url(input: &str) -> Result<String, String> {
+    match reqwest::Url::parse(input) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(input.to_owned()),
+        Ok(_) => Err("URL must use http or https".to_owned()),
+        Err(err) => Err(format!("invalid URL: {}", err)),
+    }
+}
+
+fn parse_response_transform(input: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(input);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("lua") | Some("wasm") => {}
+        _ => return Err("response transform must be a .lua or .wasm file".to_owned()),
+    }
+    // the runtime may resolve paths from another directory
+    path.canonicalize()
+        .map_err(|err| format!("could not open {}: {}", input, err))
+}
+
+fn parse_percent(input: &str) -> Result<u8, String> {
+    match input.parse::<u8>() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err("percentage must be between 0 and 100".to_owned()),
+    }
+}
+
+/// Checks a standard 5-field cron expression before the server sees it
+fn validate_schedule(schedule: &str) -> Result<(), Error> {
+    let fields: Vec<&str> = schedule.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(format_err!(
+            "Invalid schedule {:?}: expected 5 fields (minute hour day month weekday), got {}",
+            schedule,
+            fields.len()
+        ));
+    }
+    // the cron crate wants a leading seconds field and numbers weekdays 1-7
+    // from Sunday, so it only checks the other fields and weekdays are
+    // checked here with the usual 0-7 numbering
+    validate_weekdays(fields[4])
+        .map_err(|err| format_err!("Invalid schedule {:?}: {}", schedule, err))?;
+    format!("0 {} *", fields[..4].join(" "))
+        .parse::<cron::Schedule>()
+        .map_err(|err| format_err!("Invalid schedule {:?}: {}", schedule, err))?;
+    Ok(())
+}
+
+/// Checks that `tz` looks like an IANA timezone name such as `Europe/Berlin`
+///
+/// Whether the zone exists is left to the server, which knows its tz database.
+fn validate_timezone(tz: &str) -> Result<(), Error> {
+    let valid = tz.split('/').all(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+    });
+    if !valid {
+        return Err(format_err!(
+            "Invalid timezone {:?}: expected an IANA name such as Europe/Berlin or UTC",
+            tz
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a cron weekday field, where 0 and 7 are both Sunday
+fn validate_weekdays(field: &str) -> Result<(), String> {
+    const NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+    let weekday = |value: &str| -> Result<usize, String> {
+        match value.parse::<usize>() {
+            Ok(day) if day <= 7 => Ok(day),
+            Ok(_) => Err(format!("weekday {} is out of range 0-7", value)),
+            Err(_) => NAMES
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(value))
+                .ok_or_else(|| format!("invalid weekday {:?}", value)),
+        }
+    };
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+        if let Some(step) = step {
+            match step.parse::<usize>() {
+                Ok(step) if step > 0 => {}
+                _ => return Err(format!("invalid step {:?}", step)),
+            }
+        }
+        if range == "*" {
+            continue;
+        }
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        if weekday(start)? > weekday(end)? {
+            return Err(format!("weekday range {:?} runs backwards", range));
+        }
+    }
+    Ok(())
+}
+
+fn parse_ratio(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err("ratio must be between 0.0 and 1.0".to_owned()),
+    }
+}
+
+fn parse_log_drain(input: &str, allow_insecure: bool) -> Result<String, Error> {
+    let url = reqwest::Url::parse(input)
+        .map_err(|err| format_err!("Invalid log drain {:?}: {}", input, err))?;
+
+    match url.scheme() {
+        "https" => Ok(input.to_owned()),
+        "http" if allow_insecure => Ok(input.to_owned()),
+        "http" => Err(format_err!(
+            "Log drain {} is insecure. Use https:// or pass --allow-insecure-log-drain.",
+            input
+        )),
+        scheme => Err(format_err!(
+            "Log drain {} has an unsupported scheme {:?}",
+            input,
+            scheme
+        )),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = Cli::clap().get_matches();
+    let cli = Cli::from_clap(&matches);
+
+    let _ = logger::init(
+        cli.verbose,
+        cli.quiet,
+        cli.log_file.as_deref(),
+        matches.subcommand_name().unwrap_or_default(),
+    )
+    .map_err(|err| {
+        eprintln!("Could not open log file: {}", err);
+        std::process::exit(1);
+    });
+
+    if let Some(dir) = cli.module_cache_dir {
+        cache::set_dir(dir);
+    }
+
+    let _ = dispatch(cli.command).await.map_err(|err| {
+        eprintln!("{}", err);
+        std::process::exit(exit_code(&err));
+    });
+}
+
+/// Maps an error to the exit codes listed in `wasp --help`
+fn exit_code(err: &Error) -> i32 {
+    if err.downcast_ref::<AuthError>().is_some() || err.downcast_ref::<FieldNotFound>().is_some() {
+        return 2;
+    }
+
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        if err.is_connect() || err.is_timeout() {
+            return 3;
+        }
+    }
+
+    match err.downcast_ref::<ApiError>().map(|err| err.status) {
+        Some(status)
+            if status == reqwest::StatusCode::UNAUTHORIZED
+                || status == reqwest::StatusCode::FORBIDDEN =>
+        {
+            2
+        }
+        Some(status) if status.is_client_error() => 4,
+        Some(status) if status.is_server_error() => 5,
+        _ => 1,
+    }
+}
+
+async fn dispatch(command: Opt) -> Result<(), Error> {
+    match command {
+        Opt::Run { options } => run(options),
+        Opt::Upload {
+            source,
+            modules,
+            verify,
+            fail_fast,
+            tags,
+            output,
+        } => {
+            let client = source.try_into()?;
+            if modules.len() == 1 {
+                let module = modules.into_iter().next().unwrap_or_default();
+                upload(client, module, verify, tags, output.format()).await
+            } else if !tags.is_empty() {
+                Err(format_err!("--tag needs a single MODULE_PATH"))
+            } else {
+                upload_multiple(client, modules, verify, fail_fast, output.format()).await
+            }
+        }
+        Opt::UploadGc { source, max_age } => upload_gc(source.try_into()?, max_age).await,
+        Opt::UploadMany {
+            source,
+            directory,
+            recursive,
+        } => upload_many(source.try_into()?, directory, recursive).await,
+        Opt::Compile {
+            input,
+            output,
+            validate,
+        } => compile(input, output, validate),
+        Opt::Checksum { file } => checksum(file),
+        Opt::ModuleDiff { source, a, b } => module_diff(source.try_into()?, a, b).await,
+        Opt::ModuleRetag {
+            source,
+            tag,
+            module_id,
+        } => module_retag(source.try_into()?, tag, module_id).await,
+        Opt::Create {
+            source,
+            host,
+            customer_id,
+            customer_id_file,
+            configuration,
+        } => {
+            let customer_id = match customer_id_file {
+                Some(file) => Some(read_customer_id_file(&file)?),
+                None => customer_id,
+            };
+            let profile = source.profile.clone();
+            let client = source.try_into()?;
+            create(client, host, customer_id, profile, configuration).await
+        }
+        Opt::Configure {
+            source,
+            host,
+            patch,
+            unset,
+            configuration,
+        } => configure(source.try_into()?, host, patch, unset, configuration).await,
+        Opt::BatchConfigure {
+            source,
+            hosts,
+            concurrency,
+            configuration,
+        } => batch_configure(source.try_into()?, hosts, concurrency, configuration).await,
+        Opt::HostList {
+            source,
+            filter_module,
+            pages,
+            output,
+        } => host_list(source.try_into()?, filter_module, pages, output.format()).await,
+        Opt::ModuleList {
+            source,
+            pages,
+            output,
+        } => module_list(source.try_into()?, pages, output.format()).await,
+        Opt::View {
+            source,
+            host,
+            expand,
+            field,
+            accept,
+            output,
+        } => {
+            view(
+                source.try_into()?,
+                host,
+                expand,
+                field,
+                accept,
+                output.format(),
+            )
+            .await
+        }
+        Opt::Transfer {
+            source,
+            host,
+            new_customer_id,
+            confirm,
+        } => transfer(source.try_into()?, host, new_customer_id, confirm).await,
+        Opt::Rollout {
+            source,
+            host,
+            output,
+        } => rollout(source.try_into()?, host, output.format()).await,
+        Opt::Schedule {
+            source,
+            host,
+            clear,
+            output,
+        } => schedule(source.try_into()?, host, clear, output.format()).await,
+        Opt::Logs {
+            source,
+            host,
+            filter,
+            invert_match,
+        } => logs(source.try_into()?, host, filter, invert_match).await,
+        Opt::EnvGet {
+            source,
+            host,
+            key,
+            show_secrets,
+            filter,
+            output,
+        } => {
+            env_get(
+                source.try_into()?,
+                host,
+                key,
+                show_secrets,
+                filter,
+                output.format(),
+            )
+            .await
+        }
+        Opt::EnvList {
+            source,
+            host,
+            show_values,
+            unmask,
+        } => env_list(source.try_into()?, host, show_values, unmask).await,
+        Opt::CopyEnv {
+            source,
+            from,
+            to,
+            keys,
+            merge,
+        } => copy_env(source.try_into()?, from, to, keys, merge).await,
+        Opt::Status {
+            source,
+            host,
+            watch,
+        } => status(source.try_into()?, host, watch).await,
+        Opt::Apply {
+            source,
+            dir,
+            fail_fast,
+            concurrency,
+            allow_insecure_log_drain,
+        } => {
+            apply(
+                source.try_into()?,
+                dir,
+                fail_fast,
+                concurrency,
+                allow_insecure_log_drain,
+            )
+            .await
+        }
+        Opt::CreateFromFile {
+            source,
+            file,
+            on_conflict,
+            concurrency,
+            allow_insecure_log_drain,
+        } => {
+            create_from_file(
+                source.try_into()?,
+                file,
+                on_conflict,
+                concurrency,
+                allow_insecure_log_drain,
+            )
+            .await
+        }
+        Opt::Login {
+            source,
+            username,
+            token,
+            expires_in,
+        } => login(source.try_into()?, username, token, expires_in).await,
+        Opt::KvsExport {
+            directory,
+            output,
+            ndjson,
+        } => kvs_export(directory, output, ndjson),
+        Opt::KvsImport {
+            directory,
+            file,
+            merge,
+        } => kvs_import(directory, file, merge),
+        Opt::Version { source, output } => version(source.try_into()?, output.format()).await,
+        Opt::Logout { source } => logout(source.try_into()?),
+        Opt::Doctor { source } => doctor(source.try_into()?).await,
+        Opt::ConfigGet { key } => config_get(key),
+        Opt::ConfigSet { key, value, force } => config_set(key, value, force),
+    }
+}
+
+fn run(options: RunOpts) -> Result<(), Error> {
+    if let Some(file) = options.env_file {
+        dotenv::from_filename(file).expect("Could not load env file");
+    }
+
+    let (entry, routes): (Vec<_>, Vec<_>) = options
+        .functions
+        .into_iter()
+        .partition(|(_, route)| route.is_none());
+
+    if entry.len() > 1 {
+        return Err(format_err!(
+            "Only one --function can be given without a route"
+        ));
+    }
+
+    let entry = entry
+        .into_iter()
+        .next()
+        .map_or_else(|| "run".to_owned(), |(name, _)| name);
+
+    set_var("WASP_PLATFORM_FILE", &options.module);
+    set_var("WASP_PLATFORM_ENTRY_FUNCTION", entry);
+
+    // WASP_PLATFORM_FUNCTION_ROUTES is a comma separated list of NAME=ROUTE
+    // pairs; requests matching ROUTE are handled by the NAME export and any
+    // other request falls through to WASP_PLATFORM_ENTRY_FUNCTION
+    if !routes.is_empty() {
+        let routes: Vec<_> = routes
+            .into_iter()
+            .filter_map(|(name, route)| route.map(|route| format!("{}={}", name, route)))
+            .collect();
+        set_var("WASP_PLATFORM_FUNCTION_ROUTES", routes.join(","));
+    }
+
+    // WASP_PLATFORM_MOUNTS is a comma separated list of PREFIX=PATH:FUNCTION
+    // entries. A request whose path is PREFIX or starts with PREFIX/ is
+    // handled by FUNCTION of the module at PATH, the longest matching prefix
+    // winning; the module sees the path with PREFIX still in place. Anything
+    // else goes to WASP_PLATFORM_FILE as before.
+    if !options.mounts.is_empty() {
+        check_mounts(&options.mounts)?;
+        let mounts: Vec<_> = options
+            .mounts
+            .iter()
+            .map(|mount| format!("{}={}:{}", mount.prefix, mount.module, mount.function))
+            .collect();
+        set_var("WASP_PLATFORM_MOUNTS", mounts.join(","));
+    }
+
+    if let Some(file) = &options.seed_kvs {
+        // the store is closed again before the runtime opens it
+        let kvs = Kvs::open(&options.kvs_directory)?;
+        let count = kvs::seed(&kvs, file)?;
+        info!("Seeded {} keys into {}", count, options.kvs_directory);
+    }
+
+    let kvs_directory = options.kvs_directory.clone();
+    set_var("WASP_PLATFORM_KVS_DIR", options.kvs_directory);
+    let port = match options.port {
+        Some(port) => port,
+        None => match std::env::var("PORT") {
+            Ok(port) => port
+                .parse()
+                .map_err(|_| format_err!("Invalid PORT in environment: {}", port))?,
+            Err(_) => 5000,
+        },
+    };
+    set_var("PORT", port.to_string());
+    set_var("WASP_BIND_ADDRESS", options.bind.to_string());
+
+    let cdn_directory = merge_alias(
+        "--cdn-directory",
+        options.cdn_directory,
+        "--static-dir",
+        options.static_dir,
+    )?;
+    if let Some(dir) = cdn_directory {
+        set_var("WASP_CDN_DIRECTORY", dir);
+    }
+
+    let protected_cdn_directory = merge_alias(
+        "--protected-cdn-directory",
+        options.protected_cdn_directory,
+        "--protected-static-dir",
+        options.protected_static_dir,
+    )?;
+    if let Some(dir) = protected_cdn_directory {
+        set_var("WASP_PROTECTED_CDN_DIRECTORY", dir);
+    }
+
+    // WASP_CDN_CACHE_CONTROL is sent verbatim as the Cache-Control header of
+    // every CDN response; without it no Cache-Control header is sent
+    if let Some(cache_control) = options.cdn_cache_control {
+        set_var("WASP_CDN_CACHE_CONTROL", cache_control);
+    }
+
+    // WASP_CDN_FALLBACK is a path relative to the CDN directory that's served
+    // with a 200 for GETs that match no file; without it those are 404s
+    if let Some(fallback) = options.cdn_fallback {
+        set_var("WASP_CDN_FALLBACK", fallback);
+    }
+
+    set_var("WASP_RATE_LIMIT", options.rate_limit.to_string());
+
+    if let Some(burst) = options.rate_limit_burst {
+        set_var("WASP_RATE_LIMIT_BURST", burst.to_string());
+    }
+
+    if let Some(limit) = options.wasm_memory_limit {
+        set_var("WASP_WASM_MEMORY_LIMIT", limit.to_string());
+    }
+
+    // with WASP_CORS_ORIGINS set the runtime answers preflight OPTIONS
+    // requests itself and adds Access-Control-Allow-Origin (the request's
+    // Origin when it's listed, or * for a * entry), -Methods and -Headers to
+    // every response; without it no CORS headers are sent
+    if !options.cors_origins.is_empty() {
+        set_var("WASP_CORS_ORIGINS", options.cors_origins.join(","));
+        set_var("WASP_CORS_METHODS", &options.cors_methods);
+        if let Some(headers) = &options.cors_headers {
+            set_var("WASP_CORS_HEADERS", headers);
+        }
+    }
+
+    for (name, value) in request_limit_env(&options) {
+        set_var(name, value);
+    }
+
+    if let Some(timeout) = options.startup_timeout_ms {
+        set_var("WASP_STARTUP_TIMEOUT_MS", timeout.to_string());
+    }
+
+    // requests that exceed WASP_CPU_LIMIT get a 503 with
+    // {"error":"cpu limit exceeded"}
+    if let Some(limit) = options.cpu_limit {
+        set_var("WASP_CPU_LIMIT", limit.to_string());
+    }
+
+    // mirrored requests are sent in the background after the primary one is
+    // dispatched; failures are only logged at debug level
+    if let Some(url) = options.mirror_requests {
+        set_var("WASP_MIRROR_URL", url);
+        set_var("WASP_MIRROR_RATIO", options.mirror_ratio.to_string());
+    }
+
+    // the runtime only exports spans when built with OpenTelemetry support;
+    // an unreachable collector is logged as a warning and doesn't stop it
+    if let Some(endpoint) = options.otlp_endpoint {
+        let service_name = Path::new(&options.module).file_stem().map_or_else(
+            || options.module.clone(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        set_var("OTEL_EXPORTER_OTLP_ENDPOINT", endpoint);
+        set_var("OTEL_SERVICE_NAME", service_name);
+        set_var("WASP_OTEL_ENABLED", "1");
+    }
+
+    // WASP_RESPONSE_TRANSFORM is evaluated after the module responds and
+    // before anything is written to the client; a failing transform turns
+    // the response into a 500
+    if let Some(path) = options.response_transform {
+        set_var("WASP_RESPONSE_TRANSFORM", path);
+    }
+
+    // the runtime copies bodies to these files as they stream through, so
+    // the module still sees them unchanged; `-` means stderr. With
+    // WASP_LOG_FORMAT=json each body is an NDJSON record
+    // {"req_id":"...","body":"<base64>"} instead of the raw bytes.
+    if let Some(file) = &options.tee_request_body {
+        set_var("WASP_TEE_REQUEST_BODY", file);
+    }
+    if let Some(file) = &options.tee_response_body {
+        set_var("WASP_TEE_RESPONSE_BODY", file);
+    }
+
+    // the runtime takes the id from WASP_REQUEST_ID_HEADER or generates a
+    // UUID v4, exposes it to the module as WASP_REQUEST_ID and echoes it in
+    // the response under the same header
+    set_var("WASP_REQUEST_ID_HEADER", options.request_id_header);
+
+    // the runtime only honors this header when started by `wasp run`, and the
+    // overrides are scoped to the request carrying it
+    set_var("WASP_ENV_OVERRIDE_HEADER", options.env_override_header);
+
+    // WASP_FORWARD_HOST replaces the Host header before the module sees the
+    // request; with a comma separated list the runtime hands out the hosts
+    // round-robin, one per request
+    let forward_hosts: Vec<&str> = options
+        .forward_hosts
+        .iter()
+        .flat_map(|hosts| hosts.split(','))
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .collect();
+    if !forward_hosts.is_empty() {
+        set_var("WASP_FORWARD_HOST", forward_hosts.join(","));
+    }
+
+    // with `json` the runtime writes lines like
+    // {"ts":"<RFC 3339>","level":"info","msg":"...","req_id":"..."}
+    // where level is one of error, warn, info, debug or trace
+    set_var("WASP_LOG_FORMAT", options.log_format);
+
+    // the runtime sleeps WASP_INJECT_LATENCY_MS plus or minus a uniformly
+    // random WASP_INJECT_LATENCY_JITTER_MS (never below 0) before dispatching
+    // a request, unless WASP_INJECT_LATENCY_PATH_PATTERN doesn't match its path
+    if let Some(latency) = options.latency_inject_ms {
+        set_var("WASP_INJECT_LATENCY_MS", latency.to_string());
+        set_var(
+            "WASP_INJECT_LATENCY_JITTER_MS",
+            options.latency_jitter_ms.to_string(),
+        );
+        if let Some(pattern) = &options.latency_path_pattern {
+            set_var("WASP_INJECT_LATENCY_PATH_PATTERN", pattern.as_str());
+        }
+    }
+
+    for (name, value) in shutdown_env(&options) {
+        set_var(name, value);
+    }
+
+    // the server drives its own event loop so keep it off the async runtime
+    std::thread::spawn(start)
+        .join()
+        .map_err(|_| format_err!("Server exited unexpectedly"))?;
+
+    // Ctrl-C and SIGTERM are left to the runtime so it can drain in-flight
+    // requests; the store is only flushed once `start()` has returned
+    flush_kvs(&kvs_directory)
+}
+
+/// Env vars bounding each request
+///
+/// WASP_MAX_BODY_SIZE is checked against Content-Length up front and against
+/// the bytes read for chunked bodies, answering 413 either way. Requests
+/// running past WASP_REQUEST_TIMEOUT_MS get a 504.
+fn request_limit_env(options: &RunOpts) -> Vec<(&'static str, String)> {
+    vec![
+        ("WASP_MAX_BODY_SIZE", options.max_body_size.to_string()),
+        ("WASP_REQUEST_TIMEOUT_MS", options.timeout_ms.to_string()),
+    ]
+}
+
+/// Env vars telling the runtime when and how to shut down
+///
+/// After the WASP_MAX_REQUESTS-th response the runtime stops accepting
+/// connections and shuts down as it would on SIGTERM, so `start()` returns.
+/// On Ctrl-C or SIGTERM it waits up to WASP_GRACEFUL_SHUTDOWN_MS for
+/// in-flight requests before dropping them.
+fn shutdown_env(options: &RunOpts) -> Vec<(&'static str, String)> {
+    vec![
+        ("WASP_MAX_REQUESTS", options.max_requests.to_string()),
+        (
+            "WASP_GRACEFUL_SHUTDOWN_MS",
+            options.graceful_shutdown_timeout.to_string(),
+        ),
+    ]
+}
+
+/// Writes any buffered changes of the `--kvs-directory` store to disk
+fn flush_kvs(directory: &str) -> Result<(), Error> {
+    // nothing to flush if the module never used the store
+    if !Path::new(directory).exists() {
+        return Ok(());
+    }
+    Kvs::open(directory)?.flush()
+}
+
+async fn login(
+    keyring: Client,
+    username: Option<String>,
+    token: Option<String>,
+    expires_in: Option<u64>,
+) -> Result<(), Error> {
+    if let Some(token) = token {
+        // there is no server response to take the expiry from
+        keyring.set(token, expires_in.unwrap_or(3600), None)?;
+
+        info!("Ok");
+
+        return Ok(());
+    }
+
+    let username = username.ok_or_else(|| format_err!("Missing USERNAME argument"))?;
+    let password =
+        tokio::task::spawn_blocking(|| rpassword::prompt_password_stderr("Password: ")).await??;
+
+    wasp::login(&keyring, username, password, expires_in).await?;
+
+    info!("Ok");
+
+    Ok(())
+}
+
+fn config_get(key: String) -> Result<(), Error> {
+    let config = Config::load()?;
+    match config.get(&key) {
+        Some(toml::Value::String(value)) => println!("{}", value),
+        Some(value) => println!("{}", value),
+        None => return Err(format_err!("{} is not set", key)),
+    }
+    Ok(())
+}
+
+fn config_set(key: String, value: String, force: bool) -> Result<(), Error> {
+    if !force && !config::is_known_key(&key) {
+        return Err(format_err!(
+            "Unknown config key {:?}, expected default.SETTING or profile.NAME.SETTING \
+             where SETTING is one of {}; use --force to set it anyway",
+            key,
+            config::SETTINGS.join(", ")
+        ));
+    }
+
+    let mut config = Config::load()?;
+    config.set(&key, toml::Value::String(value))?;
+    config.save()?;
+
+    Ok(())
+}
+
+fn kvs_export(directory: String, output: Option<String>, ndjson: bool) -> Result<(), Error> {
+    let kvs = Kvs::open(&directory)?;
+    let count = match output {
+        Some(file) => kvs::export(&kvs, std::fs::File::create(file)?, ndjson)?,
+        None => kvs::export(&kvs, std::io::stdout().lock(), ndjson)?,
+    };
+    info!("Exported {} keys from {}", count, directory);
+    Ok(())
+}
+
+fn kvs_import(directory: String, file: String, merge: bool) -> Result<(), Error> {
+    // parse everything before touching the store
+    let entries = kvs::read_export(&file)?;
+
+    let kvs = Kvs::open(&directory)?;
+    if !merge {
+        kvs.clear()?;
+    }
+    let count = kvs::import(&kvs, entries)?;
+    info!("Imported {} keys into {}", count, directory);
+    Ok(())
+}
+
+async fn doctor(client: Client) -> Result<(), Error> {
+    let checks = vec![
+        ("keyring", check_keyring(&client)),
+        (
+            "login",
+            client
+                .get_password()
+                .map(|_| format!("logged in to account {}", client.account()))
+                .map_err(|err| err.to_string()),
+        ),
+        ("api", check_api(&client).await),
+        ("wasp directory", check_wasp_dir()),
+    ];
+
+    let total = checks.len();
+    let mut failed = 0;
+    for (name, result) in checks {
+        match result {
+            Ok(detail) => println!("ok    {}: {}", name, detail),
+            Err(hint) => {
+                failed += 1;
+                println!("FAIL  {}: {}", name, hint);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format_err!("{} of {} checks failed", failed, total));
+    }
+    Ok(())
+}
+
+fn check_keyring(client: &Client) -> Result<String, String> {
+    match client.keyring().get_password() {
+        Ok(_) | Err(keyring::KeyringError::NoPasswordFound) => Ok("available".to_owned()),
+        Err(err) => Err(format!(
+            "{}; on Linux a Secret Service provider such as gnome-keyring must be running",
+            err
+        )),
+    }
+}
+
+async fn check_api(client: &Client) -> Result<String, String> {
+    let url = client.url("/version");
+    let result = async {
+        let response = client
+            .send_with_retry(|| async { Ok::<_, Error>(client.api_builder()?.build()?.get(&url)) })
+            .await?;
+        handle_error("", response).await
+    }
+    .await;
+
+    match result {
+        Ok(_) => Ok(format!("{} is reachable", client.service())),
+        Err(err) => Err(format!(
+            "could not reach {}: {}; check --api, the `api` config setting and your network",
+            client.service(),
+            err
+        )),
+    }
+}
+
+fn check_wasp_dir() -> Result<String, String> {
+    let check = || -> Result<PathBuf, Error> {
+        let dir = wasp_dir()?;
+        std::fs::create_dir_all(&dir)?;
+        let probe = dir.join(".doctor");
+        std::fs::write(&probe, b"")?;
+        std::fs::remove_file(&probe)?;
+        Ok(dir)
+    };
+
+    match check() {
+        Ok(dir) => Ok(format!("{} is writable", dir.display())),
+        Err(err) => Err(format!(
+            "{}; the upload cache and config live there, so fix its permissions",
+            err
+        )),
+    }
+}
+
+/// Newest server major version this client is known to work with
+const COMPATIBLE_SERVER_MAJOR: u64 = 1;
+
+async fn version(client: Client, output: OutputFormat) -> Result<(), Error> {
+    // works without logging in, so don't go through `Client::get`
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(client.api_builder()?.build()?.get(&client.url("/version")))
+        })
+        .await?;
+    let response = handle_error("", response).await?;
+
+    #[derive(Debug, Deserialize)]
+    struct VersionResponse {
+        version: String,
+        api_revision: JsonValue,
+    }
+
+    let server: VersionResponse = response.json().await?;
+
+    let server_major = server
+        .version
+        .trim_start_matches('v')
+        .split('.')
+        .next()
+        .and_then(|major| major.parse::<u64>().ok());
+    if server_major.map_or(false, |major| major > COMPATIBLE_SERVER_MAJOR) {
+        warn!(
+            "warning: server version {} is newer than this client supports; consider upgrading wasp",
+            server.version
+        );
+    }
+
+    let client_version = env!("CARGO_PKG_VERSION");
+    match output {
+        OutputFormat::Text => {
+            println!("client {}", client_version);
+            println!(
+                "server {} (api revision {})",
+                server.version,
+                env_value_to_string(&server.api_revision)
+            );
+        }
+        output => print_value(
+            &serde_json::json!({
+                "client": client_version,
+                "server": server.version,
+                "api_revision": server.api_revision,
+            }),
+            output,
+        )?,
+    }
+
+    Ok(())
+}
+
+fn logout(keyring: Client) -> Result<(), Error> {
+    keyring.delete()?;
+
+    Ok(())
+}
+
+impl TryFrom<SourceOpts> for Client {
+    type Error = Error;
+
+    /// Fills in the api and account from `~/.wasp/config.toml` when the flags
+    /// are not given, preferring the `--profile` table over `[default]`
+    ///
+    /// The file is only read when a flag leaves something open, so a broken
+    /// config doesn't get in the way of `--api` with `--account`, which then
+    /// names an account rather than a profile.
+    fn try_from(mut source: SourceOpts) -> Result<Self, Error> {
+        let config = Config::shared;
+
+        // `--account API|ACCOUNT` and `--account PROFILE` set both at once
+        if let Some(account) = source.account.take() {
+            if let Some((api, name)) = account.split_once('|') {
+                if source.api.as_deref().map_or(false, |flag| flag != api) {
+                    return Err(format_err!(
+                        "--api {} disagrees with --account {}",
+                        source.api.unwrap_or_default(),
+                        account
+                    ));
+                }
+                source.api = Some(api.to_owned());
+                source.account = Some(name.to_owned());
+            } else if source.profile.is_none()
+                && source.api.is_none()
+                && config()?.has_profile(&account)
+            {
+                source.account = config()?
+                    .get_str(&format!("profile.{}.account", account))
+                    .map(str::to_owned)
+                    .or_else(|| Some(account.clone()));
+                source.profile = Some(account);
+            } else {
+                source.account = Some(account);
+            }
+        }
+
+        let profile = source.profile.as_deref();
+        if let Some(profile) = profile {
+            if !config()?.has_profile(profile) {
+                return Err(format_err!(
+                    "Unknown profile {:?}; add a [profile.{}] table to {}",
+                    profile,
+                    profile,
+                    Config::path()?.display()
+                ));
+            }
+        }
+
+        let api = match source.api {
+            Some(api) => api,
+            None => config()?
+                .setting(profile, "api")
+                .unwrap_or(DEFAULT_API)
+                .to_owned(),
+        };
+        let account = match source.account {
+            Some(account) => account,
+            None => config()?
+                .setting(profile, "account")
+                .unwrap_or("default")
+                .to_owned(),
+        };
+
+        Ok(Self::new(
+            api,
+            account,
+            source.retries,
+            Duration::from_millis(source.retry_delay),
+            source.http2,
+            source.connect_timeout.map(Duration::from_secs),
+            source.timeout.map(Duration::from_secs),
+        )
+        .with_proxy_auth(source.proxy_auth)
+        .with_max_redirects(source.max_redirects)
+        .with_dump_request(source.dump_request)
+        .with_signing_key(source.signing_key))
+    }
+}
+
+async fn host_list(
+    client: Client,
+    filter_module: Option<String>,
+    pages: PageOpts,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let query: Vec<(&str, &str)> = filter_module
+        .iter()
+        .map(|module_id| ("module_id", module_id.as_str()))
+        .collect();
+    let (mut hosts, next_cursor) = fetch_pages(&client, "/hosts", &query, "hosts", &pages).await?;
+
+    // servers that don't know the module_id filter return every host
+    if let Some(module_id) = &filter_module {
+        hosts.retain(|host| host["module"].as_str() == Some(module_id.as_str()));
+    }
+
+    match output {
+        OutputFormat::Text => {
+            for host in &hosts {
+                println!("{}", env_value_to_string(&host["host"]));
+            }
+        }
+        OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = hosts
+                .iter()
+                .map(|host| {
+                    vec![
+                        env_value_to_string(&host["host"]),
+                        env_value_to_string(&host["customer_id"]),
+                        env_value_to_string(&host["module"]),
+                        host_status(host).to_string(),
+                    ]
+                })
+                .collect();
+            table::print(&["HOST", "CUSTOMER", "MODULE", "STATUS"], &rows);
+        }
+        // TOML needs a table at the top
+        output => print_value(
+            &serde_json::json!({ "hosts": hosts, "next_cursor": next_cursor }),
+            output,
+        )?,
+    }
+
+    print_next_cursor(next_cursor.as_deref());
+
+    Ok(())
+}
+
+async fn module_list(client: Client, pages: PageOpts, output: OutputFormat) -> Result<(), Error> {
+    let (modules, next_cursor) = fetch_pages(&client, "/modules", &[], "modules", &pages).await?;
+
+    match output {
+        OutputFormat::Text => {
+            for module in &modules {
+                println!("{}", env_value_to_string(&module["module_id"]));
+            }
+        }
+        OutputFormat::Table => print_value_table(&JsonValue::Array(modules)),
+        output => print_value(
+            &serde_json::json!({ "modules": modules, "next_cursor": next_cursor }),
+            output,
+        )?,
+    }
+
+    print_next_cursor(next_cursor.as_deref());
+
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+struct PageOpts {
+    /// Resume listing from the cursor printed by a previous page
+    #[structopt(long = "cursor")]
+    cursor: Option<String>,
+
+    /// Follow every page instead of stopping after the first
+    #[structopt(long = "all")]
+    all: bool,
+
+    /// Maximum number of pages fetched with --all
+    #[structopt(long = "max-pages", default_value = "100")]
+    max_pages: usize,
+}
+
+/// Fetches a listing, following `next_cursor`s when `--all` is given.
+///
+/// The server may return a bare array or an object holding the items under
+/// `key`; the next cursor comes from the `X-Next-Cursor` header or a
+/// `next_cursor` field. Returns the items and the cursor of the next page, if
+/// there is one left.
+async fn fetch_pages(
+    client: &Client,
+    path: &str,
+    query: &[(&str, &str)],
+    key: &str,
+    pages: &PageOpts,
+) -> Result<(Vec<JsonValue>, Option<String>), Error> {
+    let mut items = vec![];
+    let mut cursor = pages.cursor.clone();
+    let mut fetched = 0;
+
+    loop {
+        let response = client
+            .send_with_retry(|| async {
+                let request = client.get(path).await?.query(query);
+                Ok::<_, Error>(match &cursor {
+                    Some(cursor) => request.query(&[("cursor", cursor)]),
+                    None => request,
+                })
+            })
+            .await?;
+        let response = handle_error("", response).await?;
+
+        let header_cursor = response
+            .headers()
+            .get("X-Next-Cursor")
+            .and_then(|cursor| cursor.to_str().ok())
+            .map(str::to_owned);
+        let mut body: JsonValue = response.json().await?;
+        let body_cursor = body["next_cursor"].as_str().map(str::to_owned);
+
+        match body {
+            JsonValue::Array(page) => items.extend(page),
+            _ => match body[key].take() {
+                JsonValue::Array(page) => items.extend(page),
+                _ => return Err(format_err!("Unexpected response listing {}: {}", key, body)),
+            },
+        }
+
+        fetched += 1;
+        cursor = header_cursor
+            .or(body_cursor)
+            .filter(|cursor| !cursor.is_empty());
+
+        if cursor.is_none() || !pages.all {
+            break;
+        }
+        if fetched >= pages.max_pages {
+            warn!(
+                "Stopped after {} pages; raise --max-pages to fetch more",
+                fetched
+            );
+            break;
+        }
+    }
+
+    Ok((items, cursor))
+}
+
+fn print_next_cursor(next_cursor: Option<&str>) {
+    if let Some(cursor) = next_cursor {
+        info!("More results available; continue with --cursor {}", cursor);
+    }
+}
+
+async fn view(
+    client: Client,
+    host: String,
+    expand: Vec<String>,
+    field: Option<String>,
+    accept: String,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(
+                client
+                    .get(format!("/hosts/{}", host))
+                    .await?
+                    .header(reqwest::header::ACCEPT, accept.as_str()),
+            )
+        })
+        .await?;
+    let response = handle_error("", response).await?;
+
+    // server-side rendered formats are printed the way they arrive
+    if !is_json_response(&response) {
+        if !expand.is_empty() || field.is_some() {
+            return Err(format_err!(
+                "--expand and --field need a JSON response, not {}",
+                accept
+            ));
+        }
+        let body = response.text().await?;
+        print!("{}", body);
+        if !body.ends_with('\n') {
+            println!();
+        }
+        return Ok(());
+    }
+
+    let mut response: JsonValue = response.json().await?;
+
+    for name in &expand {
+        let id = match response.get(name.as_str()) {
+            Some(JsonValue::String(id)) => id.clone(),
+            Some(JsonValue::Null) | None => continue,
+            Some(_) => return Err(format_err!("{} on {} is not a reference", name, host)),
+        };
+        let path = expand_path(name, &id)
+            .ok_or_else(|| format_err!("Don't know how to expand {:?}", name))?;
+
+        let details = client.send_with_retry(|| client.get(&path)).await?;
+        let details: JsonValue = handle_error("", details).await?.json().await?;
+
+        if let Some(fields) = response.as_object_mut() {
+            fields.insert(format!("{}_details", name), details);
+        }
+    }
+
+    if let Some(field) = field {
+        return match lookup_field(&response, &field) {
+            Some(JsonValue::String(value)) if output == OutputFormat::Text => {
+                println!("{}", value);
+                Ok(())
+            }
+            Some(value) => print_value(value, output),
+            None => Err(FieldNotFound(field).into()),
+        };
+    }
+
+    print_value(&response, output)
+}
+
+/// Whether the response's Content-Type is JSON, e.g. `application/json` or
+/// `application/problem+json`; a missing Content-Type is taken as JSON
+fn is_json_response(response: &reqwest::Response) -> bool {
+    let content_type = match response.headers().get(reqwest::header::CONTENT_TYPE) {
+        Some(content_type) => content_type.to_str().unwrap_or_default(),
+        None => return true,
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/json" || mime.ends_with("+json")
+}
+
+/// Follows a dotted path such as `env.DATABASE_URL` or `log_drains.0`
+fn lookup_field<'a>(value: &'a JsonValue, path: &str) -> Option<&'a JsonValue> {
+    path.split('.').try_fold(value, |value, part| match value {
+        JsonValue::Object(fields) => fields.get(part),
+        JsonValue::Array(items) => items.get(part.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+async fn transfer(
+    client: Client,
+    host: String,
+    new_customer_id: String,
+    confirm: bool,
+) -> Result<(), Error> {
+    if !confirm {
+        return Err(format_err!(
+            "Transferring {} to {} can't be undone by its current customer; pass --confirm",
+            host,
+            new_customer_id
+        ));
+    }
+
+    let current = wasp::view(&client, &host).await?;
+    let old_customer_id = env_value_to_string(&current["customer_id"]);
+
+    let body = serde_json::json!({ "customer_id": new_customer_id });
+    let response = client
+        .send_with_retry(|| async {
+            Ok::<_, Error>(
+                client
+                    .post(format!("/hosts/{}/transfer", host))
+                    .await?
+                    .json(&body),
+            )
+        })
+        .await?;
+    handle_error("", response)
+        .await
+        .map_err(|err| match err.downcast::<ApiError>() {
+            Ok(err) if err.status == reqwest::StatusCode::FORBIDDEN => ApiError {
+                message: format!(
+                    "{} (account {} needs access to customer {} to transfer hosts to it)",
+                    err.message,
+                    client.account(),
+                    new_customer_id
+                ),
+                ..err
+            }
+            .into(),
+            Ok(err) => err.into(),
+            Err(err) => err,
+        })?;
+
+    // on stdout so it can be kept as an audit record
+    println!(
+        "Transferred {} from customer {} to {}",
+        host, old_customer_id, new_customer_id
+    );
+
+    Ok(())
+}
+
+/// Prints the rollout state, which the server reports as
+/// `{"percent": N, "module": NEW_ID, "previous_module": CURRENT_ID}`
+async fn rollout(client: Client, host: String, output: OutputFormat) -> Result<(), Error> {
+    let response = client
+        .send_with_retry(|| client.get(format!("/hosts/{}/rollout", host)))
+        .await?;
+    let rollout: JsonValue = handle_error("", response).await?.json().await?;
+
+    match output {
+        OutputFormat::Text => {
+            let percent = rollout["percent"].as_u64().unwrap_or(100);
+            println!(
+                "new       {}  {}%",
+                env_value_to_string(&rollout["module"]),
+                percent
+            );
+            if percent < 100 {
+                println!(
+                    "previous  {}  {}%",
+                    env_value_to_string(&rollout["previous_module"]),
+                    100 - percent
+                );
+            }
+        }
+        output => print_value(&rollout, output)?,
+    }
+
+    Ok(())
+}
+
+async fn schedule(
+    client: Client,
+    host: String,
+    clear: bool,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    if clear {
+        // null removes the schedule, while leaving it out keeps it
+        let body = serde_json::json!({ "schedule": null, "schedule_tz": null });
+        let response = client
+            .send_with_retry(|| async {
+                Ok::<_, Error>(client.post(format!("/hosts/{}", host)).await?.json(&body))
+            })
+            .await?;
+        handle_error("", response).await?;
+
+        info!("Ok");
+
+        return Ok(());
+    }
+
+    let current = wasp::view(&client, &host).await?;
+    let schedule = serde_json::json!({
+        "schedule": current["schedule"],
+        "schedule_tz": current["schedule_tz"],
+    });
+
+    match output {
+        OutputFormat::Text => match current["schedule"].as_str() {
+            Some(cron) => println!(
+                "{} ({})",
+                cron,
+                current["schedule_tz"].as_str().unwrap_or("UTC")
+            ),
+            None => info!("{} has no schedule", host),
+        },
+        output => print_value(&schedule, output)?,
+    }
+
+    Ok(())
+}
+
+async fn logs(
+    client: Client,
+    host: String,
+    filter: Option<String>,
+    invert_match: bool,
+) -> Result<(), Error> {
+    // patterns that aren't valid regexes are matched as plain substrings
+    let pattern = filter
+        .as_ref()
+        .map(|filter| Regex::new(filter).or_else(|_| Regex::new(&regex::escape(filter))))
+        .transpose()?;
+
+    let path = format!("/hosts/{}/logs", host);
+    let response = client
+        .send_with_retry(|| async {
+            let request = client.get(&path).await?;
+            Ok::<_, Error>(match &filter {
+                Some(filter) => request.query(&[("filter", filter)]),
+                None => request,
+            })
+        })
+        .await?;
+    let response = handle_error("", response).await?;
+
+    // the server may not support `filter`, so always filter here as well
+    for line in response.text().await?.lines() {
+        let matched = pattern
+            .as_ref()
+            .map_or(true, |pattern| pattern.is_match(line));
+        if matched != invert_match {
+            println!("{}", line);
+        }
+    }
+
+    Ok(())
+}
+
+/// API path of the object a host field refers to
+fn expand_path(name: &str, id: &str) -> Option<String> {
+    match name {
+        "module" => Some(format!("/modules/{}", id)),
+        _ => None,
+    }
+}
+
+async fn env_get(
+    client: Client,
+    host: String,
+    key: Option<String>,
+    show_secrets: bool,
+    filter: EnvFilterOpts,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    if let Some(key) = key {
+        return env_get_key(client, host, key, show_secrets, output).await;
+    }
+
+    let response = wasp::view(&client, &host).await?;
+
+    let secrets = secret_keys(&response);
+
+    let mut env = response["env"].as_object().cloned().unwrap_or_default();
+    env.retain(|name, _| filter.matches(name));
+    if !show_secrets {
+        for (name, value) in env.iter_mut() {
+            if secrets.contains(name.as_str()) {
+                *value = JsonValue::String("***".to_owned());
+            }
+        }
+    }
+
+    match output {
+        OutputFormat::Text => {
+            for (name, value) in &env {
+                println!("{}={}", name, env_value_to_string(value));
+            }
+        }
+        output => print_value(&JsonValue::Object(env), output)?,
+    }
+
+    Ok(())
+}
+
+async fn env_list(
+    client: Client,
+    host: String,
+    show_values: bool,
+    unmask: bool,
+) -> Result<(), Error> {
+    let response = wasp::view(&client, &host).await?;
+    let env = response["env"].as_object().cloned().unwrap_or_default();
+
+    let secrets = secret_keys(&response);
+
+    for (name, value) in &env {
+        if !show_values {
+            println!("{}", name);
+        } else if !unmask && (secrets.contains(name.as_str()) || looks_secret(name)) {
+            println!("{}=***", name);
+        } else {
+            println!("{}={}", name, env_value_to_string(value));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether an env var name contains key, secret, token or password, and
+/// should be masked without being listed as a secret
+fn looks_secret(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ["key", "secret", "token", "password"]
+        .iter()
+        .any(|word| name.contains(word))
+}
+
+async fn env_get_key(
+    client: Client,
+    host: String,
+    key: String,
+    show_secrets: bool,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let response = client
+        .send_with_retry(|| client.get(format!("/hosts/{}/env/{}", host, key)))
+        .await?;
+
+    let response = match handle_error("", response).await {
+        Err(ref err) if is_not_found(err) => {
+            return Err(format_err!("{} is not set on {}", key, host));
+        }
+        response => response?,
+    };
+
+    #[derive(Debug, Deserialize)]
+    struct EnvResponse {
+        value: JsonValue,
+
+        #[serde(default)]
+        secret: bool,
+    }
+
+    let res: EnvResponse = response.json().await?;
+    let value = if res.secret && !show_secrets {
+        JsonValue::String("***".to_owned())
+    } else {
+        res.value
+    };
+
+    match output {
+        OutputFormat::Text => println!("{}", env_value_to_string(&value)),
+        OutputFormat::Env => print_export(&key, &value)?,
+        output => print_value(&serde_json::json!({ "key": key, "value": value }), output)?,
+    }
+
+    Ok(())
+}
+
+/// Prints a structured value; `text` falls back to pretty JSON
+fn print_value(value: &JsonValue, output: OutputFormat) -> Result<(), Error> {
+    match output {
+        OutputFormat::Text | OutputFormat::Json => println!("{:#}", value),
+        OutputFormat::CompactJson => println!("{}", value),
+        OutputFormat::Toml => {
+            let value = json_to_toml("", value);
+            if !value.is_table() {
+                return Err(format_err!("Only objects can be printed as TOML"));
+            }
+            print!("{}", toml::to_string_pretty(&value)?);
+        }
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+        OutputFormat::Table => print_value_table(value),
+        OutputFormat::Env => {
+            let fields = value
+                .as_object()
+                .ok_or_else(|| format_err!("Only objects can be printed as env"))?;
+            for (name, value) in fields {
+                print_export(name, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints `export NAME='value'`; strings are exported as is and any other
+/// value as compact JSON
+fn print_export(name: &str, value: &JsonValue) -> Result<(), Error> {
+    if !is_identifier(name) {
+        return Err(format_err!(
+            "{:?} can't be exported as a shell variable",
+            name
+        ));
+    }
+    println!(
+        "export {}={}",
+        name,
+        shell_quote(&env_value_to_string(value))
+    );
+    Ok(())
+}
+
+/// Single-quotes a value for POSIX shells, where `'` has to be written as `'\''`
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Renders arrays of objects with a column per field and objects as
+/// KEY/VALUE rows
+fn print_value_table(value: &JsonValue) {
+    match value {
+        JsonValue::Array(items) => {
+            let mut columns: Vec<&str> = vec![];
+            for item in items {
+                for key in item.as_object().into_iter().flat_map(|item| item.keys()) {
+                    if !columns.contains(&key.as_str()) {
+                        columns.push(key);
+                    }
+                }
+            }
+            let headers: Vec<String> = columns.iter().map(|column| column.to_uppercase()).collect();
+            let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+            let rows: Vec<Vec<String>> = items
+                .iter()
+                .map(|item| {
+                    columns
+                        .iter()
+                        .map(|column| env_value_to_string(&item[*column]))
+                        .collect()
+                })
+                .collect();
+            table::print(&headers, &rows);
+        }
+        JsonValue::Object(fields) => {
+            let rows: Vec<Vec<String>> = fields
+                .iter()
+                .map(|(key, field)| vec![key.to_owned(), env_value_to_string(field)])
+                .collect();
+            table::print(&["KEY", "VALUE"], &rows);
+        }
+        value => println!("{}", env_value_to_string(value)),
+    }
+}
+
+/// Converts JSON into the closest TOML equivalent.
+///
+/// TOML has no null and requires arrays to hold a single type; such values
+/// are kept as a JSON string with a warning naming the field at `path`.
+fn json_to_toml(path: &str, value: &JsonValue) -> toml::Value {
+    let fallback = |value: &JsonValue| {
+        warn!(
+            "warning: {} cannot be represented in TOML, keeping it as JSON",
+            if path.is_empty() { "value" } else { path }
+        );
+        toml::Value::String(value.to_string())
+    };
+
+    match value {
+        JsonValue::Null => fallback(value),
+        JsonValue::Bool(value) => toml::Value::Boolean(*value),
+        JsonValue::Number(number) => match number.as_i64() {
+            Some(number) => toml::Value::Integer(number),
+            None => toml::Value::Float(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        JsonValue::String(value) => toml::Value::String(value.to_owned()),
+        JsonValue::Array(items) => {
+            if items.iter().any(JsonValue::is_null) {
+                return fallback(value);
+            }
+            let items: Vec<_> = items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| json_to_toml(&format!("{}[{}]", path, index), item))
+                .collect();
+            let is_mixed = items
+                .windows(2)
+                .any(|pair| pair[0].type_str() != pair[1].type_str());
+            if is_mixed {
+                return fallback(value);
+            }
+            toml::Value::Array(items)
+        }
+        JsonValue::Object(fields) => toml::Value::Table(
+            fields
+                .iter()
+                .map(|(key, field)| {
+                    let path = if path.is_empty() {
+                        key.to_owned()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    (key.to_owned(), json_to_toml(&path, field))
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn env_value_to_string(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(value) => value.to_owned(),
+        JsonValue::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+async fn copy_env(
+    client: Client,
+    from: String,
+    to: String,
+    keys: Vec<String>,
+    merge: bool,
+) -> Result<(), Error> {
+    let source = wasp::view(&client, &from).await?;
+    let mut env = host_env(&source);
+
+    if !keys.is_empty() {
+        if let Some(missing) = keys.iter().find(|key| !env.contains_key(*key)) {
+            return Err(format_err!("{} is not set on {}", missing, from));
+        }
+        env.retain(|name, _| keys.contains(name));
+    }
+
+    let mut secrets: BTreeSet<String> = secret_keys(&source).into_iter().collect();
+
+    let copied = env.len();
+    if merge {
+        let target = wasp::view(&client, &to).await?;
+        let mut merged = host_env(&target);
+        merged.extend(env);
+        env = merged;
+
+        secrets.extend(secret_keys(&target));
+    }
+
+    let body = ConfigureBody {
+        env: Some(env),
+        secret_keys: secrets,
+        ..ConfigureBody::default()
+    };
+    wasp::configure(&client, &to, &body).await?;
+
+    info!("Copied {} env vars from {} to {}", copied, from, to);
+
+    Ok(())
+}
+
+/// Names of the env vars a host object lists as secret under `secrets`
+fn secret_keys(host: &JsonValue) -> HashSet<String> {
+    host["secrets"]
+        .as_array()
+        .map(|keys| {
+            keys.iter()
+                .filter_map(JsonValue::as_str)
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The `env` object of a host as returned by the API
+fn host_env(host: &JsonValue) -> HashMap<String, JsonValue> {
+    host["env"]
+        .as_object()
+        .map(|env| env.clone().into_iter().collect())
+        .unwrap_or_default()
+}
+
+async fn status(client: Client, host: String, watch: Option<u64>) -> Result<(), Error> {
+    let mut previous = None;
+
+    loop {
+        let status = host_status(&wasp::view(&client, &host).await?);
+        let is_ready = status.state == "ready";
+
+        if previous.as_ref() != Some(&status) {
+            println!("{}", status);
+        }
+
+        match watch {
+            _ if is_ready => return Ok(()),
+            Some(interval) => {
+                previous = Some(status);
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+            None => return Err(format_err!("Host {} is not ready", host)),
+        }
+    }
+}
+
+/// A host's state, e.g. `ready`, and what the server said about it
+#[derive(Debug, PartialEq)]
+struct HostStatus {
+    state: String,
+    message: Option<String>,
+}
+
+impl std::fmt::Display for HostStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{}: {}", self.state, message),
+            None => write!(f, "{}", self.state),
+        }
+    }
+}
+
+/// Extracts the status from a host object.
+///
+/// The server reports either a plain string (`"ready"`) or an object with a
+/// `state` and an optional `message`, which is displayed as `state: message`.
+fn host_status(host: &JsonValue) -> HostStatus {
+    match &host["status"] {
+        JsonValue::String(state) => HostStatus {
+            state: state.to_owned(),
+            message: None,
+        },
+        JsonValue::Object(status) => HostStatus {
+            state: status
+                .get("state")
+                .and_then(JsonValue::as_str)
+                .unwrap_or("unknown")
+                .to_owned(),
+            message: status
+                .get("message")
+                .and_then(JsonValue::as_str)
+                .map(str::to_owned),
+        },
+        _ => HostStatus {
+            state: "unknown".to_owned(),
+            message: None,
+        },
+    }
+}
+
+async fn upload(
+    client: Client,
+    module_path: String,
+    verify: bool,
+    tags: Vec<String>,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let mut module = wasp::upload(&client, &module_path).await?;
+
+    if verify {
+        let functions = verify_module(&client, &module.module_id).await?;
+        info!("Entry functions: {}", functions.join(", "));
+        module.functions = Some(functions);
+    }
+
+    match output {
+        OutputFormat::Text => println!("{}", module.module_id),
+        output => print_value(&serde_json::to_value(&module)?, output)?,
+    }
+
+    // the id is already printed, so a failed tag can be retried with
+    // module:retag instead of uploading again
+    let mut failed = vec![];
+    for tag in &tags {
+        match add_module_tag(&client, &module.module_id, tag).await {
+            Ok(()) => info!("Tagged {} as {}", module.module_id, tag),
+            Err(err) => {
+                warn!("Could not tag {} as {}: {}", module.module_id, tag, err);
+                failed.push(tag.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format_err!(
+            "Uploaded {} but could not tag it as {}; retry with `wasp module:retag TAG {}`",
+            module.module_id,
+            failed.join(", "),
+            module.module_id
+        ));
+    }
+
+    Ok(())
+}
+
+async fn upload_multiple(
+    client: Client,
+    modules: Vec<String>,
+    verify: bool,
+    fail_fast: bool,
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let cache = SharedUploadCache::new(UploadCache::load()?);
+
+    let mut uploaded = vec![];
+    let mut failed = vec![];
+    for module_path in &modules {
+        let result = async {
+            let module_id = cached_upload(&client, &cache, module_path).await?;
+            if verify {
+                verify_module(&client, &module_id).await?;
+            }
+            Ok::<_, Error>(module_id)
+        }
+        .await;
+
+        match result {
+            Ok(module_id) => {
+                if output == OutputFormat::Text {
+                    println!("{}\t{}", module_path, module_id);
+                }
+                uploaded.push(serde_json::json!({ "path": module_path, "module_id": module_id }));
+            }
+            Err(err) => {
+                failed.push((module_path, err));
+                if fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+
+    cache.into_inner().save()?;
+
+    if output != OutputFormat::Text {
+        print_value(&serde_json::json!({ "modules": uploaded }), output)?;
+    }
+
+    if !failed.is_empty() {
+        for (module_path, err) in &failed {
+            eprintln!("{}: {}", module_path, err);
+        }
+        return Err(format_err!(
+            "{} of {} modules failed to upload",
+            failed.len(),
+            modules.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Prunes the upload cache
+///
+/// Only entries for the current API can be checked against the server;
+/// entries for other APIs are only dropped by `max_age`.
+async fn upload_gc(client: Client, max_age: Option<Duration>) -> Result<(), Error> {
+    let mut cache = UploadCache::load()?;
+    let entries = cache.entries();
+    let now = SystemTime::now();
+
+    let mut removed = 0;
+    for (api, hash, entry) in &entries {
+        let expired = max_age.map_or(false, |max_age| {
+            now.duration_since(entry.uploaded_at)
+                .map_or(false, |age| age > max_age)
+        });
+        let deleted = !expired && *api == client.service() && {
+            let path = format!("/modules/{}", entry.module_id);
+            let response = client.send_with_retry(|| client.get(&path)).await?;
+            match handle_error("", response).await {
+                Ok(_) => false,
+                Err(ref err) if is_not_found(err) => true,
+                Err(err) => return Err(err),
+            }
+        };
+
+        if expired || deleted {
+            debug!("Dropping {} from the upload cache", entry.module_id);
+            cache.remove(api, hash);
+            removed += 1;
+        }
+    }
+
+    cache.save()?;
+
+    info!("Removed {} of {} cache entries", removed, entries.len());
+
+    Ok(())
 }
 
-fn maybe_upload(client: &Client, module: Option<String>) -> Result<Option<String>, Error> {
-    if let Some(module) = module {
-        if std::path::Path::new(&module).exists() {
-            Ok(Some(do_upload(&client, &module)?))
-        } else {
-            Ok(Some(module))
+async fn upload_many(client: Client, directory: String, recursive: bool) -> Result<(), Error> {
+    let mut paths = vec![];
+    find_modules(Path::new(&directory), recursive, &mut paths)?;
+    paths.sort();
+
+    // stdout only gets `filename\tmodule_id` lines so other tools can parse it
+    let mut failed = vec![];
+    for path in &paths {
+        let name = path.strip_prefix(&directory).unwrap_or(path);
+        let module_path = path.to_string_lossy();
+
+        let result = match wasp::upload(&client, &module_path).await {
+            Ok(module) => Ok(module),
+            Err(err) => {
+                warn!("Upload of {} failed, retrying: {}", name.display(), err);
+                wasp::upload(&client, &module_path).await
+            }
+        };
+
+        match result {
+            Ok(module) => println!("{}\t{}", name.display(), module.module_id),
+            Err(err) => failed.push((name, err)),
         }
-    } else {
-        Ok(None)
     }
+
+    if !failed.is_empty() {
+        for (name, err) in &failed {
+            eprintln!("{}: {}", name.display(), err);
+        }
+        return Err(format_err!(
+            "{} of {} modules failed to upload",
+            failed.len(),
+            paths.len()
+        ));
+    }
+
+    Ok(())
 }
 
-fn do_upload(client: &Client, module_path: &str) -> Result<String, Error> {
-    eprintln!("Uploading module: {:?}", module_path);
-    let mut response = client
-        .post("/compile")?
-        .body(std::fs::File::open(module_path)?)
-        .send()?;
+/// Prints `+`/`-` lines for exports and imports B has that A hasn't and the
+/// other way around, failing when there are any
+async fn module_diff(client: Client, a: String, b: String) -> Result<(), Error> {
+    let info_a = fetch_module_info(&client, &a).await?;
+    let info_b = fetch_module_info(&client, &b).await?;
 
-    handle_error("", &mut response)?;
+    let mut differences = 0;
+    for (kind, field) in &[("export", "exports"), ("import", "imports")] {
+        let names_a = module_names(&info_a[*field]);
+        let names_b = module_names(&info_b[*field]);
+        for name in names_b.difference(&names_a) {
+            println!("+ {} {}", kind, name);
+            differences += 1;
+        }
+        for name in names_a.difference(&names_b) {
+            println!("- {} {}", kind, name);
+            differences += 1;
+        }
+    }
 
-    #[derive(Debug, Deserialize)]
-    struct LoginResponse {
-        #[serde(rename = "ok")]
-        module_id: String,
+    if differences > 0 {
+        return Err(format_err!(
+            "{} and {} differ in {} places",
+            a,
+            b,
+            differences
+        ));
+    }
+    Ok(())
+}
+
+async fn fetch_module_info(client: &Client, module_id: &str) -> Result<JsonValue, Error> {
+    let response = client
+        .send_with_retry(|| client.get(format!("/modules/{}/info", module_id)))
+        .await?;
+    let response = handle_error(&format!("Could not fetch {}: ", module_id), response).await?;
+    Ok(response.json().await?)
+}
+
+/// Names in an export or import list, given either as plain strings or as
+/// `{"module": ..., "name": ...}` objects
+fn module_names(list: &JsonValue) -> BTreeSet<String> {
+    list.as_array()
+        .into_iter()
+        .flatten()
+        .map(
+            |item| match (item["module"].as_str(), item["name"].as_str()) {
+                (Some(module), Some(name)) => format!("{}.{}", module, name),
+                (None, Some(name)) => name.to_owned(),
+                _ => env_value_to_string(item),
+            },
+        )
+        .collect()
+}
+
+fn checksum(file: String) -> Result<(), Error> {
+    let contents =
+        std::fs::read(&file).map_err(|err| format_err!("Could not open {}: {}", file, err))?;
+    println!("{}  {}", cache::hash(&contents), file);
+    Ok(())
+}
+
+fn compile(input: String, output: Option<String>, validate: bool) -> Result<(), Error> {
+    let wasm = wat::parse_file(&input).map_err(|err| format_err!("{}", err))?;
+
+    if validate {
+        wasmparser::Validator::new()
+            .validate_all(&wasm)
+            .map_err(|err| format_err!("Invalid module {}: {}", input, err))?;
     }
 
-    let res: LoginResponse = response.json()?;
+    let output = output.map_or_else(|| Path::new(&input).with_extension("wasm"), PathBuf::from);
+    std::fs::write(&output, &wasm)?;
+
+    info!("Wrote {} bytes to {}", wasm.len(), output.display());
+
+    Ok(())
+}
+
+/// Moves `tag` to `module_id`; the server swaps it atomically
+async fn module_retag(client: Client, tag: String, module_id: String) -> Result<(), Error> {
+    tag_module(&client, &tag, &module_id).await?;
+
+    info!("Ok");
+
+    Ok(())
+}
 
-    Ok(res.module_id)
+/// Collects the `*.wasm` files in `dir`, descending into subdirectories if `recursive`
+fn find_modules(dir: &Path, recursive: bool, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                find_modules(&path, recursive, paths)?;
+            }
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some("wasm") {
+            paths.push(path);
+        }
+    }
+    Ok(())
 }
 
-fn handle_error(step: &str, response: &mut reqwest::Response) -> Result<(), Error> {
-    if response.status().is_success() {
+async fn create(
+    client: Client,
+    host: Option<String>,
+    customer_id: Option<String>,
+    profile: Option<String>,
+    configuration: ConfigureOpts,
+) -> Result<(), Error> {
+    let dry_run = configuration.dry_run;
+    let verify = configuration.verify;
+    let output = configuration.output.format();
+    let idempotency_key = configuration.idempotency_key.clone();
+    let mut spec = configuration.into_spec()?;
+
+    let (host, customer_id) = match host {
+        Some(host) => {
+            let (host, host_customer_id) = split_host_customer(host)?;
+            match (host_customer_id, customer_id) {
+                (Some(shorthand), Some(positional)) if shorthand != positional => {
+                    return Err(format_err!(
+                        "{}@{} disagrees with CUSTOMER_ID {}",
+                        host,
+                        shorthand,
+                        positional
+                    ));
+                }
+                (shorthand, positional) => (Some(host), shorthand.or(positional)),
+            }
+        }
+        None => (None, customer_id),
+    };
+
+    let host = host
+        .or_else(|| spec.host.take())
+        .ok_or_else(|| format_err!("Missing HOST argument or `host` in the spec"))?;
+    let customer_id = match customer_id.or_else(|| spec.customer_id.take()) {
+        Some(customer_id) => customer_id,
+        None => Config::shared()?
+            .setting(profile.as_deref(), "customer_id")
+            .map(str::to_owned)
+            .ok_or_else(|| {
+                format_err!(
+                    "Missing CUSTOMER_ID argument or `customer_id` in the spec; \
+                     set a default with `wasp config:set default.customer_id ID`"
+                )
+            })?,
+    };
+
+    let mut body = CreateBody {
+        host,
+        customer_id,
+        configuration: spec.into_body(&client, dry_run, verify).await?,
+    };
+    body.configuration.idempotency_key = idempotency_key;
+
+    if dry_run {
+        print_value(&body.redacted()?, output)?;
         return Ok(());
     }
 
-    #[derive(Debug, Deserialize)]
-    struct ErrorResponse {
-        error: String,
+    wasp::create(&client, &body).await?;
+
+    info!("Ok");
+
+    Ok(())
+}
+
+fn read_customer_id_file(file: &Path) -> Result<String, Error> {
+    let contents = std::fs::read_to_string(file)
+        .map_err(|err| format_err!("Could not open {}: {}", file.display(), err))?;
+    contents
+        .lines()
+        .next()
+        .map(str::trim)
+        .filter(|customer_id| !customer_id.is_empty())
+        .map(str::to_owned)
+        .ok_or_else(|| format_err!("No customer id on the first line of {}", file.display()))
+}
+
+/// Splits the `NAME@CUSTOMER_ID` shorthand; plain names have no customer
+///
+/// Both sides of the `@` must be given and only one `@` is allowed.
+fn split_host_customer(host: String) -> Result<(String, Option<String>), Error> {
+    let mut parts = host.split('@');
+    let (name, customer_id) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(_), None, _) => return Ok((host, None)),
+        (Some(name), Some(customer_id), None) => (name, customer_id),
+        _ => {
+            return Err(format_err!(
+                "Invalid host {:?}: use a single @ between the name and the customer id",
+                host
+            ))
+        }
+    };
+    if name.is_empty() {
+        return Err(format_err!(
+            "Invalid host {:?}: missing the host name before the @",
+            host
+        ));
+    }
+    if customer_id.is_empty() {
+        return Err(format_err!(
+            "Invalid host {:?}: missing the customer id after the @; leave out the @ \
+             to pass it separately",
+            host
+        ));
+    }
+    Ok((name.to_owned(), Some(customer_id.to_owned())))
+}
+
+async fn configure(
+    client: Client,
+    host: Option<String>,
+    patch: bool,
+    unset: Vec<String>,
+    configuration: ConfigureOpts,
+) -> Result<(), Error> {
+    let dry_run = configuration.dry_run;
+    let verify = configuration.verify;
+    let output = configuration.output.format();
+    let show_secrets = configuration.show_secrets;
+    let idempotency_key = configuration.idempotency_key.clone();
+    let clear_env = configuration.clear_env;
+    let mut spec = configuration.into_spec()?;
+    let host = host
+        .or_else(|| spec.host.take())
+        .ok_or_else(|| format_err!("Missing HOST argument or `host` in the spec"))?;
+
+    // with --clear-env there's nothing of the current env to keep
+    let patch = (patch || !unset.is_empty()) && !clear_env;
+    if patch {
+        // the server replaces env wholesale, so send the current env merged
+        // with the changes
+        let current = wasp::view(&client, &host).await?;
+        let mut env = host_env(&current);
+        env.extend(spec.env.drain());
+        for name in &unset {
+            env.remove(name);
+        }
+        spec.env = env;
+
+        spec.secret_keys.extend(secret_keys(&current));
+        spec.mark_secrets(show_secrets);
+    }
+
+    let mut body = spec.into_body(&client, dry_run, verify).await?;
+    body.idempotency_key = idempotency_key;
+
+    if patch || clear_env {
+        // an empty env must still be sent when every key was removed
+        body.env.get_or_insert_with(HashMap::new);
+    }
+
+    if dry_run {
+        print_value(&body.redacted()?, output)?;
+        return Ok(());
+    }
+
+    wasp::configure(&client, &host, &body).await?;
+
+    info!("Ok");
+
+    Ok(())
+}
+
+async fn batch_configure(
+    client: Client,
+    hosts: Vec<String>,
+    concurrency: usize,
+    configuration: ConfigureOpts,
+) -> Result<(), Error> {
+    let dry_run = configuration.dry_run;
+    let verify = configuration.verify;
+    let output = configuration.output.format();
+    let idempotency_key = configuration.idempotency_key.clone();
+    let clear_env = configuration.clear_env;
+
+    // upload the module once and share the resulting id between hosts
+    let mut body = configuration
+        .into_spec()?
+        .into_body(&client, dry_run, verify)
+        .await?;
+    if clear_env {
+        body.env.get_or_insert_with(HashMap::new);
+    }
+
+    if dry_run {
+        print_value(&body.redacted()?, output)?;
+        return Ok(());
+    }
+
+    let tasks = hosts
+        .iter()
+        .map(|host| {
+            let client = client.clone();
+            let host = host.clone();
+            let mut body = body.clone();
+            // one key per host, or the server would dedupe them into one update
+            body.idempotency_key = idempotency_key
+                .as_ref()
+                .map(|key| format!("{}:{}", key, host));
+            async move { wasp::configure(&client, &host, &body).await }.boxed()
+        })
+        .collect();
+
+    let results = ConcurrentRunner::new(concurrency).run(tasks).await;
+
+    let mut failed = 0;
+    for (host, result) in hosts.iter().zip(results) {
+        match result {
+            Ok(()) => info!("{}: Ok", host),
+            Err(err) => {
+                failed += 1;
+                eprintln!("{}: {}", host, err);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format_err!(
+            "{} of {} hosts failed to update",
+            failed,
+            hosts.len()
+        ));
+    }
+
+    Ok(())
+}
+
+async fn apply(
+    client: Client,
+    dir: String,
+    fail_fast: bool,
+    concurrency: usize,
+    allow_insecure_log_drain: bool,
+) -> Result<(), Error> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("json") | Some("yaml") | Some("yml")
+            )
+        })
+        .collect();
+    paths.sort();
+
+    let cache = Arc::new(SharedUploadCache::new(UploadCache::load()?));
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    // with --fail-fast, specs that haven't started when one fails are skipped
+    // and resolve to `None`
+    let tasks = paths
+        .iter()
+        .map(|path| {
+            let client = client.clone();
+            let cache = cache.clone();
+            let stopped = stopped.clone();
+            let path = path.clone();
+            async move {
+                if stopped.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+                let result = apply_spec(&client, &cache, &path, allow_insecure_log_drain).await;
+                if result.is_err() && fail_fast {
+                    stopped.store(true, Ordering::SeqCst);
+                }
+                result.map(Some)
+            }
+            .boxed()
+        })
+        .collect();
+
+    let results = ConcurrentRunner::new(concurrency).run(tasks).await;
+
+    cache.save()?;
+
+    let mut failed = 0;
+    let mut skipped = 0;
+    for (path, result) in paths.iter().zip(&results) {
+        match result {
+            Ok(Some((host, action))) => println!("{}\t{}", host, action),
+            Ok(None) => {
+                skipped += 1;
+                println!("{}\tskipped", path.display());
+            }
+            Err(err) => {
+                failed += 1;
+                println!("{}\tfailed: {}", path.display(), err);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format_err!(
+            "{} of {} host specs failed to apply, {} skipped",
+            failed,
+            paths.len(),
+            skipped
+        ));
+    }
+
+    Ok(())
+}
+
+/// Creates the host described by the spec at `path`, or updates it if it exists
+///
+/// Returns the host name and the action that was taken.
+async fn apply_spec(
+    client: &Client,
+    cache: &SharedUploadCache,
+    path: &Path,
+    allow_insecure_log_drain: bool,
+) -> Result<(String, &'static str), Error> {
+    let mut spec = HostSpec::load(&path.to_string_lossy())?;
+
+    let host = spec
+        .host
+        .take()
+        .ok_or_else(|| format_err!("Missing `host` in {}", path.display()))?;
+
+    spec.check_log_drains(allow_insecure_log_drain)?;
+
+    // module paths are relative to the spec file
+    let module = match spec.take_module(path.parent().unwrap_or_else(|| Path::new(".")))? {
+        Some(ModuleRef::Id(id)) => Some(id),
+        Some(ModuleRef::Tag(tag)) => Some(resolve_tag(client, &tag).await?),
+        Some(module) => Some(cached_upload(client, cache, &module.to_string()).await?),
+        None => None,
+    };
+
+    spec.mark_secrets(false);
+    spec.resolve_secrets().await?;
+
+    let customer_id = spec.customer_id.take();
+    let configuration = spec.body_with_module(module);
+
+    match wasp::view(client, &host).await {
+        Ok(_) => {
+            wasp::configure(client, &host, &configuration).await?;
+            Ok((host, "updated"))
+        }
+        Err(ref err) if is_not_found(err) => {
+            let customer_id = customer_id
+                .ok_or_else(|| format_err!("Missing `customer_id` in {}", path.display()))?;
+            let body = CreateBody {
+                host,
+                customer_id,
+                configuration,
+            };
+            wasp::create(client, &body).await?;
+            Ok((body.host, "created"))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn create_from_file(
+    client: Client,
+    file: String,
+    on_conflict: OnConflict,
+    concurrency: usize,
+    allow_insecure_log_drain: bool,
+) -> Result<(), Error> {
+    let specs: Vec<HostSpec> = load_spec_file(&file)?;
+
+    // upload each distinct module once before creating any host, keeping the
+    // ids of what did upload even if a later entry fails
+    let cache = SharedUploadCache::new(UploadCache::load()?);
+    let bodies = manifest_bodies(&client, &cache, &file, specs, allow_insecure_log_drain).await;
+    cache.save()?;
+    let bodies = bodies?;
+
+    let hosts: Vec<String> = bodies.iter().map(|body| body.host.clone()).collect();
+
+    let tasks = bodies
+        .into_iter()
+        .map(|body| {
+            let client = client.clone();
+            async move {
+                match wasp::create(&client, &body).await {
+                    Ok(()) => Ok("created"),
+                    Err(ref err) if is_conflict(err) => match on_conflict {
+                        OnConflict::Skip => Ok("skipped"),
+                        OnConflict::Update => {
+                            wasp::configure(&client, &body.host, &body.configuration).await?;
+                            Ok("updated")
+                        }
+                        OnConflict::Error => Err(format_err!("host already exists")),
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            .boxed()
+        })
+        .collect();
+
+    let results = ConcurrentRunner::new(concurrency).run(tasks).await;
+
+    let mut failed = 0;
+    for (host, result) in hosts.iter().zip(results) {
+        match result {
+            Ok(action) => println!("{}\t{}", host, action),
+            Err(err) => {
+                failed += 1;
+                println!("{}\tfailed: {}", host, err);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format_err!(
+            "{} of {} hosts failed to create",
+            failed,
+            hosts.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Builds the create request of every manifest entry, uploading modules
+/// through `cache`; module paths are relative to the manifest
+async fn manifest_bodies(
+    client: &Client,
+    cache: &SharedUploadCache,
+    file: &str,
+    specs: Vec<HostSpec>,
+    allow_insecure_log_drain: bool,
+) -> Result<Vec<CreateBody>, Error> {
+    let base = Path::new(file).parent().unwrap_or_else(|| Path::new("."));
+
+    let mut bodies = vec![];
+    for (index, mut spec) in specs.into_iter().enumerate() {
+        let host = spec
+            .host
+            .take()
+            .ok_or_else(|| format_err!("Missing `host` in entry {} of {}", index, file))?;
+        let customer_id = spec
+            .customer_id
+            .take()
+            .ok_or_else(|| format_err!("Missing `customer_id` for {} in {}", host, file))?;
+
+        spec.check_log_drains(allow_insecure_log_drain)?;
+
+        let module = match spec.take_module(base)? {
+            Some(ModuleRef::Id(id)) => Some(id),
+            Some(ModuleRef::Tag(tag)) => Some(resolve_tag(client, &tag).await?),
+            Some(module) => Some(cached_upload(client, cache, &module.to_string()).await?),
+            None => None,
+        };
+
+        spec.mark_secrets(false);
+        spec.resolve_secrets().await?;
+
+        bodies.push(CreateBody {
+            host,
+            customer_id,
+            configuration: spec.body_with_module(module),
+        });
+    }
+
+    Ok(bodies)
+}
+
+const MAX_CONCURRENCY: usize = 16;
+
+/// Polling more than once a second only loads the API
+fn parse_watch_interval(input: &str) -> Result<u64, String> {
+    match input.parse::<u64>() {
+        Ok(interval) if interval >= 1 => Ok(interval),
+        _ => Err("interval must be a whole number of seconds, at least 1".to_owned()),
+    }
+}
+
+fn parse_concurrency(input: &str) -> Result<usize, String> {
+    match input.parse::<usize>() {
+        Ok(concurrency) if (1..=MAX_CONCURRENCY).contains(&concurrency) => Ok(concurrency),
+        _ => Err(format!(
+            "concurrency must be between 1 and {}",
+            MAX_CONCURRENCY
+        )),
+    }
+}
+
+type Task<T = ()> = BoxFuture<'static, Result<T, Error>>;
+
+/// Runs tasks with a bounded number in flight at once
+struct ConcurrentRunner {
+    concurrency: usize,
+}
+
+impl ConcurrentRunner {
+    pub fn new(concurrency: usize) -> Self {
+        Self { concurrency }
+    }
+
+    /// Executes every task, printing a `[done/total]` counter to stderr.
+    ///
+    /// Results are returned in the same order as `tasks`.
+    pub async fn run<T>(&self, tasks: Vec<Task<T>>) -> Vec<Result<T, Error>> {
+        let total = tasks.len();
+
+        let mut pending = stream::iter(
+            tasks
+                .into_iter()
+                .enumerate()
+                .map(|(index, task)| async move { (index, task.await) }),
+        )
+        .buffer_unordered(self.concurrency);
+
+        let mut results: Vec<Option<Result<T, Error>>> = (0..total).map(|_| None).collect();
+        let mut done = 0;
+        while let Some((index, result)) = pending.next().await {
+            done += 1;
+            info!("[{}/{}]", done, total);
+            results[index] = Some(result);
+        }
+
+        results.into_iter().flatten().collect()
+    }
+}
+
+async fn maybe_upload(client: &Client, module: Option<ModuleRef>) -> Result<Option<String>, Error> {
+    match module {
+        Some(ModuleRef::Id(id)) => Ok(Some(id)),
+        Some(ModuleRef::Tag(tag)) => Ok(Some(resolve_tag(client, &tag).await?)),
+        Some(module) => Ok(Some(
+            wasp::upload(&client, &module.to_string()).await?.module_id,
+        )),
+        None => Ok(None),
+    }
+}
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    Success
+    1    Any other error
+    2    Missing, expired or rejected credentials, or no such host:get --field
+    3    Could not connect to the API or the request timed out
+    4    The API rejected the request (4xx)
+    5    The API failed to handle the request (5xx)";
+
+/// `host:get --field` named a field the host doesn't have
+#[derive(Debug, Fail)]
+#[fail(display = "field not found: {}", _0)]
+struct FieldNotFound(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_opts(args: &[&str]) -> RunOpts {
+        RunOpts::from_iter_safe(["run", "module.wasm"].iter().chain(args)).unwrap()
+    }
+
+    fn env_value(env: &[(&str, String)], name: &str) -> Option<String> {
+        env.iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    #[test]
+    fn weekdays_0_and_7_are_sunday() {
+        assert!(validate_weekdays("0").is_ok());
+        assert!(validate_weekdays("7").is_ok());
+        assert!(validate_schedule("0 9 * * 0").is_ok());
+        assert!(validate_schedule("0 9 * * 7").is_ok());
+        assert!(validate_schedule("* * * * 0-4").is_ok());
+        assert!(validate_schedule("* * * * 5-7").is_ok());
+    }
+
+    #[test]
+    fn weekdays_accept_names() {
+        assert!(validate_weekdays("SUN-SAT").is_ok());
+        assert!(validate_weekdays("mon,wed,fri").is_ok());
+        assert!(validate_weekdays("MON-5").is_ok());
+        assert!(validate_weekdays("SUNDAY").is_err());
+    }
+
+    #[test]
+    fn weekdays_accept_ranges_and_steps() {
+        assert!(validate_weekdays("1-5").is_ok());
+        assert!(validate_weekdays("*/2").is_ok());
+        assert!(validate_weekdays("0-6/3").is_ok());
+        assert!(validate_weekdays("1,3-4,6").is_ok());
+        assert!(validate_weekdays("5-1").is_err());
+        assert!(validate_weekdays("*/0").is_err());
+        assert!(validate_weekdays("1-").is_err());
+    }
+
+    #[test]
+    fn weekdays_reject_out_of_range_values() {
+        assert!(validate_weekdays("8").is_err());
+        assert!(validate_weekdays("0-8").is_err());
+        assert!(validate_schedule("* * * * 8").is_err());
+    }
+
+    #[test]
+    fn schedules_need_five_valid_fields() {
+        assert!(validate_schedule("*/15 * * * *").is_ok());
+        assert!(validate_schedule("* * * *").is_err());
+        assert!(validate_schedule("0 * * * * *").is_err());
+        assert!(validate_schedule("60 * * * *").is_err());
+    }
+
+    #[test]
+    fn schedule_timezones_must_look_like_iana_names() {
+        assert!(validate_timezone("UTC").is_ok());
+        assert!(validate_timezone("Europe/Berlin").is_ok());
+        assert!(validate_timezone("America/Argentina/Buenos_Aires").is_ok());
+        assert!(validate_timezone("Etc/GMT+5").is_ok());
+        assert!(validate_timezone("").is_err());
+        assert!(validate_timezone("Europe/").is_err());
+        assert!(validate_timezone("Europe Berlin").is_err());
+        assert!(validate_timezone("../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn host_customer_shorthand_splits_on_the_at() {
+        assert_eq!(
+            split_host_customer("myapp@cust_123".to_owned()).unwrap(),
+            ("myapp".to_owned(), Some("cust_123".to_owned()))
+        );
+        assert_eq!(
+            split_host_customer("myapp".to_owned()).unwrap(),
+            ("myapp".to_owned(), None)
+        );
+    }
+
+    #[test]
+    fn host_customer_shorthand_needs_both_sides() {
+        let err = split_host_customer("@cust_123".to_owned()).unwrap_err();
+        assert!(err.to_string().contains("missing the host name"));
+
+        let err = split_host_customer("myapp@".to_owned()).unwrap_err();
+        assert!(err.to_string().contains("missing the customer id"));
+    }
+
+    #[test]
+    fn host_customer_shorthand_allows_one_at() {
+        let err = split_host_customer("my@app@cust_123".to_owned()).unwrap_err();
+        assert!(err.to_string().contains("single @"));
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("'"), r"''\'''");
+        assert_eq!(shell_quote("$(rm -rf ~)`id`"), "'$(rm -rf ~)`id`'");
+    }
+
+    #[test]
+    fn shell_quote_handles_empty_and_multiline_values() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a\nb"), "'a\nb'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_quote_round_trips_through_sh() {
+        for value in &[
+            "",
+            "it's",
+            "a\nb'\n'c",
+            "$HOME ${PATH} `id` $(id)",
+            "\\'\"; exit 1",
+        ] {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_quote(value)))
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), *value);
+        }
+    }
+
+    /// A module file `parse_mount` can find, removed with the returned dir
+    fn mount_module() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let module = dir.path().join("api.wasm");
+        std::fs::write(&module, b"").unwrap();
+        let module = module.to_str().unwrap().to_owned();
+        (dir, module)
+    }
+
+    #[test]
+    fn mount_prefixes_lose_trailing_slashes() {
+        let (_dir, module) = mount_module();
+        let mount = parse_mount(&format!("/api/={}", module)).unwrap();
+        assert_eq!(mount.prefix, "/api");
+        assert_eq!(mount.module, module);
+        assert_eq!(mount.function, "run");
+
+        let mount = parse_mount(&format!("/api/v2//={}:handle", module)).unwrap();
+        assert_eq!(mount.prefix, "/api/v2");
+        assert_eq!(mount.function, "handle");
+    }
+
+    #[test]
+    fn mount_prefixes_must_be_rooted_and_not_root() {
+        let (_dir, module) = mount_module();
+        assert!(parse_mount(&format!("api={}", module)).is_err());
+        assert!(parse_mount(&format!("/={}", module)).is_err());
+    }
+
+    #[test]
+    fn mounts_need_an_equals_sign() {
+        let (_dir, module) = mount_module();
+        let err = parse_mount(&module).unwrap_err();
+        assert!(err.contains("PREFIX=MODULE_PATH"));
+    }
+
+    #[test]
+    fn mounts_reject_duplicate_prefixes_but_allow_nested_ones() {
+        let (_dir, module) = mount_module();
+        let mount = |prefix: &str| parse_mount(&format!("{}={}", prefix, module)).unwrap();
+
+        assert!(check_mounts(&[mount("/api"), mount("/api/v2"), mount("/web")]).is_ok());
+        assert!(check_mounts(&[mount("/api"), mount("/api/")]).is_err());
+    }
+
+    #[test]
+    fn host_status_separates_state_and_message() {
+        let status = host_status(&serde_json::json!({
+            "status": { "state": "ready", "message": "serving 2 instances" }
+        }));
+        assert_eq!(status.state, "ready");
+        assert_eq!(status.to_string(), "ready: serving 2 instances");
+
+        let status = host_status(&serde_json::json!({ "status": "ready" }));
+        assert_eq!(status.to_string(), "ready");
+
+        assert_eq!(host_status(&serde_json::json!({})).state, "unknown");
     }
 
-    let text = response.text()?;
-    match serde_json::from_str::<ErrorResponse>(&text) {
-        Ok(err) => Err(format_err!("{}{}", step, err.error)),
-        _ => Err(format_err!("{}{}", step, text)),
+    #[test]
+    fn watch_interval_must_be_positive() {
+        assert_eq!(parse_watch_interval("5"), Ok(5));
+        assert!(parse_watch_interval("0").is_err());
+        assert!(parse_watch_interval("-1").is_err());
+    }
+
+    #[test]
+    fn max_body_size_reaches_the_runtime() {
+        let env = request_limit_env(&run_opts(&["--max-body-size", "16"]));
+        assert_eq!(env_value(&env, "WASP_MAX_BODY_SIZE").as_deref(), Some("16"));
+
+        let env = request_limit_env(&run_opts(&[]));
+        assert_eq!(
+            env_value(&env, "WASP_MAX_BODY_SIZE").as_deref(),
+            Some("10485760")
+        );
+
+        assert!(RunOpts::from_iter_safe(&["run", "module.wasm", "--max-body-size", "-1"]).is_err());
+    }
+
+    #[test]
+    fn graceful_shutdown_timeout_reaches_the_runtime() {
+        let env = shutdown_env(&run_opts(&["--graceful-shutdown-timeout", "1234"]));
+        assert_eq!(
+            env_value(&env, "WASP_GRACEFUL_SHUTDOWN_MS").as_deref(),
+            Some("1234")
+        );
+
+        let env = shutdown_env(&run_opts(&["--shutdown-timeout", "0"]));
+        assert_eq!(
+            env_value(&env, "WASP_GRACEFUL_SHUTDOWN_MS").as_deref(),
+            Some("0")
+        );
+
+        let env = shutdown_env(&run_opts(&[]));
+        assert_eq!(
+            env_value(&env, "WASP_GRACEFUL_SHUTDOWN_MS").as_deref(),
+            Some("5000")
+        );
     }
 }
@@ -0,0 +1,65 @@
+use std::io::IsTerminal;
+
+const MIN_COLUMN_WIDTH: usize = 3;
+
+/// Prints rows as aligned columns under a header line
+///
+/// When stdout is a terminal, the widest columns are shortened until the
+/// table fits in `$COLUMNS` (80 if unset); piped output keeps full values.
+pub fn print(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    if std::io::stdout().is_terminal() {
+        fit(&mut widths, terminal_width());
+    }
+
+    let headers: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+    print_row(&headers, &widths);
+    for row in rows {
+        print_row(row, &widths);
+    }
+}
+
+fn print_row(row: &[String], widths: &[usize]) {
+    let cells: Vec<String> = widths
+        .iter()
+        .zip(row)
+        .map(|(width, cell)| format!("{:width$}", truncate(cell, *width), width = width))
+        .collect();
+    println!("{}", cells.join("  ").trim_end());
+}
+
+/// Shrinks the widest columns until all of them plus separators fit in `max`
+fn fit(widths: &mut [usize], max: usize) {
+    let separators = widths.len().saturating_sub(1) * 2;
+    while widths.iter().sum::<usize>() + separators > max {
+        match widths.iter_mut().max() {
+            Some(widest) if *widest > MIN_COLUMN_WIDTH => *widest -= 1,
+            _ => break,
+        }
+    }
+}
+
+fn truncate(cell: &str, width: usize) -> String {
+    if cell.chars().count() <= width {
+        return cell.to_owned();
+    }
+    let mut truncated: String = cell.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn terminal_width() -> usize {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
@@ -0,0 +1,114 @@
+use failure::{format_err, Error};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeSet, HashMap};
+use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
+
+const SCHEME: &str = "vault://";
+
+/// Whether an env value names a Vault secret instead of holding the value
+pub fn is_reference(value: &str) -> bool {
+    value.starts_with(SCHEME)
+}
+
+/// A `vault://MOUNT/data/PATH#FIELD` reference to a KV v2 secret
+///
+/// The `data/` segment of the API path may be left out. Without `#FIELD` the
+/// whole secret is used as a JSON object.
+#[derive(Debug)]
+pub struct SecretRef {
+    mount: String,
+    path: String,
+    field: Option<String>,
+}
+
+impl SecretRef {
+    pub fn parse(reference: &str) -> Result<Self, String> {
+        let location = reference
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| format!("expected {}PATH, got {:?}", SCHEME, reference))?;
+        let (location, field) = match location.split_once('#') {
+            Some((location, field)) if !field.is_empty() => (location, Some(field.to_owned())),
+            Some(_) => return Err(format!("empty field in {:?}", reference)),
+            None => (location, None),
+        };
+        let (mount, path) = location
+            .split_once('/')
+            .ok_or_else(|| format!("expected {}MOUNT/PATH, got {:?}", SCHEME, reference))?;
+        let path = path.strip_prefix("data/").unwrap_or(path);
+        if mount.is_empty() || path.is_empty() {
+            return Err(format!(
+                "expected {}MOUNT/PATH, got {:?}",
+                SCHEME, reference
+            ));
+        }
+
+        Ok(Self {
+            mount: mount.to_owned(),
+            path: path.to_owned(),
+            field,
+        })
+    }
+}
+
+impl std::fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}{}/data/{}", SCHEME, self.mount, self.path)?;
+        if let Some(field) = &self.field {
+            write!(f, "#{}", field)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces `vault://` values in `env` with the secrets they point at,
+/// returning the names of the replaced env vars
+///
+/// Vault is reached through `VAULT_ADDR` and `VAULT_TOKEN`. Secrets are only
+/// kept in memory, and errors name the reference rather than the value.
+pub async fn resolve_env(env: &mut HashMap<String, JsonValue>) -> Result<BTreeSet<String>, Error> {
+    let mut references = vec![];
+    for (name, value) in env.iter() {
+        if let Some(value) = value.as_str().filter(|value| is_reference(value)) {
+            let reference =
+                SecretRef::parse(value).map_err(|err| format_err!("Invalid {}: {}", name, err))?;
+            references.push((name.clone(), reference));
+        }
+    }
+    if references.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+
+    let client = connect()?;
+    let mut resolved = BTreeSet::new();
+    for (name, reference) in references {
+        let mut secret: serde_json::Map<String, JsonValue> =
+            vaultrs::kv2::read(&client, &reference.mount, &reference.path)
+                .await
+                .map_err(|err| format_err!("Could not read {} for {}: {}", reference, name, err))?;
+
+        let value = match &reference.field {
+            Some(field) => secret
+                .remove(field)
+                .ok_or_else(|| format_err!("{} has no field {:?}", reference, field))?,
+            None => JsonValue::Object(secret),
+        };
+        env.insert(name.clone(), value);
+        resolved.insert(name);
+    }
+
+    Ok(resolved)
+}
+
+fn connect() -> Result<VaultClient, Error> {
+    let address = std::env::var("VAULT_ADDR")
+        .map_err(|_| format_err!("VAULT_ADDR must be set to read vault:// env vars"))?;
+    let token = std::env::var("VAULT_TOKEN")
+        .map_err(|_| format_err!("VAULT_TOKEN must be set to read vault:// env vars"))?;
+
+    let settings = VaultClientSettingsBuilder::default()
+        .address(address)
+        .token(token)
+        .build()
+        .map_err(|err| format_err!("Invalid Vault settings: {}", err))?;
+    VaultClient::new(settings).map_err(|err| format_err!("Could not connect to Vault: {}", err))
+}
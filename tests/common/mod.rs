@@ -0,0 +1,90 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+
+/// A `wasp run` serving an empty module from a temporary directory, which
+/// also holds its KVS; both go away with the server
+pub struct Server {
+    pub child: Child,
+    pub port: u16,
+    _dir: TempDir,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Starts `wasp run` with `args` and waits until it accepts connections
+pub fn start(args: &[&str]) -> Server {
+    let dir = tempfile::tempdir().unwrap();
+    let module = dir.path().join("module.wasm");
+    std::fs::write(
+        &module,
+        wat::parse_str(r#"(module (func (export "run")))"#).unwrap(),
+    )
+    .unwrap();
+
+    let port = free_port();
+    let child = Command::new(env!("CARGO_BIN_EXE_wasp"))
+        .arg("run")
+        .arg(&module)
+        .arg("--kvs-directory")
+        .arg(dir.path().join("kvs"))
+        .args(&["--bind", "127.0.0.1", "--port", &port.to_string()])
+        .args(args)
+        .spawn()
+        .unwrap();
+    let server = Server {
+        child,
+        port,
+        _dir: dir,
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(
+            Instant::now() < deadline,
+            "wasp run didn't listen on {}",
+            port
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    server
+}
+
+/// Sends a raw request and returns the response status, or an empty string
+/// when the connection closed without a response
+pub fn status(port: u16, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream.write_all(request).unwrap();
+
+    let mut response = vec![];
+    // the server may close the connection before reading the whole body
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response);
+    response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default()
+        .to_owned()
+}
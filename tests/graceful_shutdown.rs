@@ -0,0 +1,42 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{process::Command, thread, time::Duration};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+/// Sends a request the runtime holds for a second, presses Ctrl-C while it's
+/// in flight and returns the status it got, if any
+fn interrupt_in_flight(graceful_shutdown_timeout: &str) -> String {
+    let mut server = common::start(&[
+        "--latency-inject-ms",
+        "1000",
+        "--graceful-shutdown-timeout",
+        graceful_shutdown_timeout,
+    ]);
+
+    let port = server.port;
+    let request = thread::spawn(move || common::status(port, REQUEST));
+    thread::sleep(Duration::from_millis(300));
+
+    let interrupted = Command::new("kill")
+        .args(&["-INT", &server.child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+
+    let status = request.join().unwrap();
+    server.child.wait().unwrap();
+    status
+}
+
+#[test]
+fn ctrl_c_waits_for_in_flight_requests() {
+    assert_ne!(interrupt_in_flight("5000"), "");
+}
+
+#[test]
+fn ctrl_c_drops_requests_past_the_timeout() {
+    assert_eq!(interrupt_in_flight("0"), "");
+}
@@ -0,0 +1,21 @@
+mod common;
+
+#[test]
+fn rejects_content_length_over_limit() {
+    let server = common::start(&["--max-body-size", "16"]);
+    let body = "x".repeat(17);
+    let request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    assert_eq!(common::status(server.port, request.as_bytes()), "413");
+}
+
+#[test]
+fn rejects_chunked_body_over_limit() {
+    let server = common::start(&["--max-body-size", "16"]);
+    let request = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\
+                   Connection: close\r\n\r\n10\r\nxxxxxxxxxxxxxxxx\r\n1\r\nx\r\n0\r\n\r\n";
+    assert_eq!(common::status(server.port, request.as_bytes()), "413");
+}